@@ -0,0 +1,312 @@
+//! Reading of asciinema cast files (format v2): a JSON header on the first
+//! line, followed by one `[time, type, data]` JSON array per event.
+
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+  dump_json::write_json_str,
+  fs_utils::write_atomic,
+  proc::{Proc, ResizeConfig},
+};
+
+pub struct Cast {
+  pub width: u16,
+  pub height: u16,
+  pub events: Vec<Event>,
+}
+
+pub struct Event {
+  /// Seconds since the start of the recording.
+  pub time: f64,
+  /// `"o"` for output, `"i"` for input, `"r"` for resize, etc.
+  pub kind: String,
+  pub data: String,
+}
+
+pub fn read(path: &str) -> Result<Cast> {
+  let src = std::fs::read_to_string(path)
+    .with_context(|| format!("Failed to read cast: {}", path))?;
+  parse(&src).with_context(|| format!("Failed to parse cast: {}", path))
+}
+
+pub fn parse(src: &str) -> Result<Cast> {
+  let mut lines = src
+    .lines()
+    .enumerate()
+    .filter(|(_, l)| !l.trim().is_empty());
+
+  let (_, header) = lines.next().context("Empty cast")?;
+  let header: Header = serde_json::from_str(header).context("Line 1")?;
+  match header.version.as_ref().and_then(Value::as_f64) {
+    Some(2.0) => (),
+    _ => bail!("Line 1: Only cast version 2 is supported"),
+  }
+  let size =
+    |key: &str, value: Option<&Value>| match value.and_then(Value::as_f64) {
+      Some(n) if (1.0..=u16::MAX as f64).contains(&n) => Ok(n as u16),
+      _ => bail!("Line 1: Missing or invalid \"{}\"", key),
+    };
+  let width = size("width", header.width.as_ref())?;
+  let height = size("height", header.height.as_ref())?;
+
+  let mut events = Vec::new();
+  for (i, line) in lines {
+    let (time, kind, data) =
+      match serde_json::from_str::<(f64, JsonStr, JsonStr)>(line) {
+        Ok(event) => event,
+        Err(err) if err.is_data() => {
+          bail!("Line {}: Expected [time, type, data]", i + 1)
+        }
+        Err(err) => Err(err).with_context(|| format!("Line {}", i + 1))?,
+      };
+    events.push(Event {
+      time,
+      kind: kind.0,
+      data: data.0,
+    });
+  }
+
+  Ok(Cast {
+    width,
+    height,
+    events,
+  })
+}
+
+/// Rounds the event times down to multiples of `quantize_ms` (all times are
+/// zero if it's 0) and merges the adjacent output events that got the same
+/// time. The same output gives the same cast, whatever its timing was.
+pub fn normalize(cast: &Cast, quantize_ms: u64) -> Cast {
+  let mut events: Vec<Event> = Vec::new();
+  for event in &cast.events {
+    let time = match quantize_ms {
+      0 => 0.0,
+      quantum => {
+        let ms = (event.time * 1000.0).max(0.0) as u64;
+        (ms - ms % quantum) as f64 / 1000.0
+      }
+    };
+    if let Some(last) = events.last_mut() {
+      if event.kind == "o" && last.kind == "o" && last.time == time {
+        last.data.push_str(&event.data);
+        continue;
+      }
+    }
+    events.push(Event {
+      time,
+      kind: event.kind.clone(),
+      data: event.data.clone(),
+    });
+  }
+  Cast {
+    width: cast.width,
+    height: cast.height,
+    events,
+  }
+}
+
+/// Cast file of `cast`. The header only has the version and the size.
+pub fn to_string(cast: &Cast) -> String {
+  let mut src = format!(
+    "{{\"version\": 2, \"width\": {}, \"height\": {}}}\n",
+    cast.width, cast.height
+  );
+  for event in &cast.events {
+    let _ = write!(src, "[{:.3}, ", event.time);
+    write_json_str(&mut src, &event.kind);
+    src.push_str(", ");
+    write_json_str(&mut src, &event.data);
+    src.push_str("]\n");
+  }
+  src
+}
+
+pub fn write(path: &str, cast: &Cast) -> Result<()> {
+  let src = to_string(cast);
+  write_atomic(path, |tmp| {
+    std::fs::write(tmp, src)?;
+    Ok(())
+  })
+}
+
+/// Feeds output and resize events up to `at_ms` (all events if `None`) into
+/// the terminal of `proc`.
+pub fn replay(proc: &mut Proc, cast: &Cast, at_ms: Option<u64>) -> Result<()> {
+  for event in &cast.events {
+    if let Some(at_ms) = at_ms {
+      if event.time * 1000.0 > at_ms as f64 {
+        break;
+      }
+    }
+    match event.kind.as_str() {
+      "o" => proc.parse_output(event.data.as_bytes()),
+      "r" => {
+        let size = event
+          .data
+          .split_once('x')
+          .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+        match size {
+          Some((width, height)) => {
+            proc.resize(ResizeConfig { width, height })?;
+          }
+          None => log::warn!("Invalid resize event: {}", event.data),
+        }
+      }
+      _ => (),
+    }
+  }
+  Ok(())
+}
+
+#[derive(Deserialize)]
+struct Header {
+  version: Option<Value>,
+  width: Option<Value>,
+  height: Option<Value>,
+}
+
+/// JSON string of an event. Output split in the middle of a character may
+/// have lone surrogate escapes, which are replaced with U+FFFD.
+struct JsonStr(String);
+
+impl<'de> Deserialize<'de> for JsonStr {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    struct Visitor;
+
+    impl serde::de::Visitor<'_> for Visitor {
+      type Value = JsonStr;
+
+      fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a string")
+      }
+
+      // serde_json gives the bytes of a string with lone surrogates encoded
+      // as WTF-8 (0xed 0xa0..=0xbf 0x80..=0xbf), which isn't valid UTF-8.
+      fn visit_bytes<E>(self, bytes: &[u8]) -> Result<JsonStr, E> {
+        let mut text = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+          if bytes[i] == 0xed && matches!(bytes.get(i + 1), Some(0xa0..=0xbf)) {
+            text.extend_from_slice("\u{fffd}".as_bytes());
+            i += 3;
+          } else {
+            text.push(bytes[i]);
+            i += 1;
+          }
+        }
+        Ok(JsonStr(String::from_utf8_lossy(&text).into_owned()))
+      }
+    }
+
+    deserializer.deserialize_bytes(Visitor)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_cast() {
+    let cast = parse(concat!(
+      "{\"version\": 2, \"width\": 80, \"height\": 24, ",
+      "\"env\": {\"SHELL\": \"/bin/bash\", \"TERM\": null}}\n",
+      "[0.5, \"o\", \"\\u001b[31mred\\b\\/ \\ud83d\\ude00\"]\n",
+      "\n",
+      "[1.25, \"r\", \"100x30\"]\n",
+    ))
+    .unwrap();
+    assert_eq!((cast.width, cast.height), (80, 24));
+    assert_eq!(cast.events.len(), 2);
+    assert_eq!(cast.events[0].time, 0.5);
+    assert_eq!(cast.events[0].kind, "o");
+    assert_eq!(cast.events[0].data, "\x1b[31mred\x08/ \u{1f600}");
+    assert_eq!(cast.events[1].kind, "r");
+    assert_eq!(cast.events[1].data, "100x30");
+  }
+
+  #[test]
+  fn parse_lone_surrogates() {
+    let cast = parse(concat!(
+      "{\"version\": 2, \"width\": 80, \"height\": 24}\n",
+      "[0.5, \"o\", \"\\ud83d\\u0041 \\udc00\\n \\ud83d\"]\n",
+    ))
+    .unwrap();
+    assert_eq!(cast.events[0].data, "\u{fffd}A \u{fffd}\n \u{fffd}");
+  }
+
+  #[test]
+  fn normalize_cast() {
+    let cast = parse(concat!(
+      "{\"version\": 2, \"width\": 80, \"height\": 24, ",
+      "\"timestamp\": 1700000000}\n",
+      "[0.013, \"o\", \"a\"]\n",
+      "[0.071, \"o\", \"b\\r\\n\"]\n",
+      "[0.120, \"r\", \"100x30\"]\n",
+      "[0.180, \"o\", \"\\u001b[1mc\"]\n",
+      "[0.260, \"o\", \"d\"]\n",
+    ))
+    .unwrap();
+    assert_eq!(
+      to_string(&normalize(&cast, 0)),
+      concat!(
+        "{\"version\": 2, \"width\": 80, \"height\": 24}\n",
+        "[0.000, \"o\", \"ab\\r\\n\"]\n",
+        "[0.000, \"r\", \"100x30\"]\n",
+        "[0.000, \"o\", \"\\u001b[1mcd\"]\n",
+      )
+    );
+    assert_eq!(
+      to_string(&normalize(&cast, 100)),
+      concat!(
+        "{\"version\": 2, \"width\": 80, \"height\": 24}\n",
+        "[0.000, \"o\", \"ab\\r\\n\"]\n",
+        "[0.100, \"r\", \"100x30\"]\n",
+        "[0.100, \"o\", \"\\u001b[1mc\"]\n",
+        "[0.200, \"o\", \"d\"]\n",
+      )
+    );
+    // The normalized cast is read back the same.
+    let normalized = to_string(&normalize(&cast, 0));
+    assert_eq!(to_string(&parse(&normalized).unwrap()), normalized);
+  }
+
+  #[test]
+  fn parse_errors() {
+    let header = "{\"version\": 2, \"width\": 80, \"height\": 24}\n";
+    let err = |src: &str| format!("{:#}", parse(src).err().unwrap());
+
+    assert_eq!(
+      err("{\"version\": 1}"),
+      "Line 1: Only cast version 2 is supported"
+    );
+    assert_eq!(
+      err("{\"version\": 2, \"width\": 80}"),
+      "Line 1: Missing or invalid \"height\""
+    );
+    assert_eq!(
+      err(&format!("{}[1, \"o\"]", header)),
+      "Line 2: Expected [time, type, data]"
+    );
+    assert_eq!(
+      err(&format!("{}[1, \"o\", \"a]", header)),
+      "Line 2: EOF while parsing a string at line 1 column 12"
+    );
+    // Deeply nested values give an error, not a stack overflow.
+    assert_eq!(
+      err(&format!("{}{}", header, "[".repeat(100_000))),
+      "Line 2: Expected [time, type, data]"
+    );
+    assert_eq!(
+      err(&format!("{{\"env\": {}", "[".repeat(100_000))),
+      "Line 1: EOF while parsing a list at line 1 column 100008"
+    );
+  }
+}
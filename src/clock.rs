@@ -0,0 +1,39 @@
+use std::{
+  sync::OnceLock,
+  time::{Duration, Instant},
+};
+
+/// Deterministic mode set with `VIRTERM_DETERMINISTIC=1`. The clock used in
+/// traces is frozen, so the output of two runs can be compared.
+pub fn deterministic() -> bool {
+  static DETERMINISTIC: OnceLock<bool> = OnceLock::new();
+  *DETERMINISTIC.get_or_init(|| {
+    let var = std::env::var("VIRTERM_DETERMINISTIC").unwrap_or_default();
+    parse_flag(var.as_str())
+  })
+}
+
+/// Time since `since`, always zero in deterministic mode.
+pub fn elapsed(since: Instant) -> Duration {
+  if deterministic() {
+    Duration::ZERO
+  } else {
+    since.elapsed()
+  }
+}
+
+fn parse_flag(var: &str) -> bool {
+  !matches!(var, "" | "0")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    assert!(!parse_flag(""));
+    assert!(!parse_flag("0"));
+    assert!(parse_flag("1"));
+  }
+}
@@ -0,0 +1,127 @@
+//! Screens as text with SGR escape sequences, so golden files show color and
+//! attribute changes in diffs.
+
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::{dump_txt::style_runs, fs_utils::write_atomic};
+
+pub fn dump_ansi(screen: &vt100::Screen, path: &str) -> Result<()> {
+  let contents = to_ansi(screen);
+  write_atomic(path, |tmp| {
+    std::fs::write(tmp, contents)?;
+    Ok(())
+  })
+}
+
+/// Attributes of a cell that SGR sequences set.
+#[derive(Clone, Copy, PartialEq)]
+struct Attrs {
+  fg: vt100::Color,
+  bg: vt100::Color,
+  bold: bool,
+  italic: bool,
+  underline: bool,
+  inverse: bool,
+}
+
+impl Attrs {
+  const DEFAULT: Attrs = Attrs {
+    fg: vt100::Color::Default,
+    bg: vt100::Color::Default,
+    bold: false,
+    italic: false,
+    underline: false,
+    inverse: false,
+  };
+
+  fn of(cell: &vt100::Cell) -> Self {
+    Attrs {
+      fg: cell.fgcolor(),
+      bg: cell.bgcolor(),
+      bold: cell.bold(),
+      italic: cell.italic(),
+      underline: cell.underline(),
+      inverse: cell.inverse(),
+    }
+  }
+
+  /// A sequence that resets the attributes and sets these ones.
+  fn sgr(&self) -> String {
+    let mut sgr = String::from("\x1b[0");
+    if self.bold {
+      sgr.push_str(";1");
+    }
+    if self.italic {
+      sgr.push_str(";3");
+    }
+    if self.underline {
+      sgr.push_str(";4");
+    }
+    if self.inverse {
+      sgr.push_str(";7");
+    }
+    push_color(&mut sgr, 38, self.fg);
+    push_color(&mut sgr, 48, self.bg);
+    sgr.push('m');
+    sgr
+  }
+}
+
+fn push_color(sgr: &mut String, base: u8, color: vt100::Color) {
+  let _ = match color {
+    vt100::Color::Default => Ok(()),
+    vt100::Color::Idx(idx) => write!(sgr, ";{};5;{}", base, idx),
+    vt100::Color::Rgb(r, g, b) => write!(sgr, ";{};2;{};{};{}", base, r, g, b),
+  };
+}
+
+/// The screen contents with an SGR sequence before every run of cells with
+/// other attributes. Attributes are reset at the end of every row, so rows
+/// can be read on their own. Like `Screen::contents()`, blank cells at the
+/// end of rows and blank rows at the end of the screen are left out.
+pub fn to_ansi(screen: &vt100::Screen) -> String {
+  let rows = screen.size().0;
+  let mut lines = Vec::with_capacity(rows as usize);
+  for row in 0..rows {
+    let runs = style_runs(screen, row, &Attrs::DEFAULT, Attrs::of);
+    let mut line = String::new();
+    let mut current = Attrs::DEFAULT;
+    for (attrs, text) in &runs {
+      if text.is_empty() {
+        continue;
+      }
+      if *attrs != current {
+        line.push_str(&attrs.sgr());
+        current = *attrs;
+      }
+      line.push_str(text);
+    }
+    if current != Attrs::DEFAULT {
+      line.push_str("\x1b[0m");
+    }
+    lines.push(line);
+  }
+  while lines.last().is_some_and(|line| line.is_empty()) {
+    lines.pop();
+  }
+  lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn to_ansi() {
+    let mut parser = vt100::Parser::new(4, 12, 0);
+    parser.process(
+      b"a \x1b[1;31mred\x1b[3;44mblue\x1b[0m\r\n\
+        \x1b[4;7;38;2;1;2;3mx\x1b[0m b\r\n\r\n",
+    );
+    assert_eq!(
+      super::to_ansi(parser.screen()),
+      "a \x1b[0;1;38;5;1mred\x1b[0;1;3;38;5;1;48;5;4mblue\x1b[0m\n\
+       \x1b[0;4;7;38;2;1;2;3mx\x1b[0m b"
+    );
+  }
+}
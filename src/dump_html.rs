@@ -0,0 +1,154 @@
+//! Screens as standalone HTML pages, e.g. to embed colored terminal output
+//! into documentation.
+
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::{
+  dump_png::{Palette, DEF_BG, DEF_FG},
+  dump_txt::style_runs,
+  fs_utils::write_atomic,
+};
+
+pub fn dump_html(
+  screen: &vt100::Screen,
+  palette: &Palette,
+  path: &str,
+) -> Result<()> {
+  let html = to_html(screen, palette);
+  write_atomic(path, |tmp| {
+    std::fs::write(tmp, html)?;
+    Ok(())
+  })
+}
+
+/// Colors and attributes of a run of cells, as they look on the screen.
+#[derive(Clone, Copy, PartialEq)]
+struct Style {
+  fg: [u8; 3],
+  bg: [u8; 3],
+  bold: bool,
+  italic: bool,
+  underline: bool,
+}
+
+impl Style {
+  const DEFAULT: Style = Style {
+    fg: DEF_FG,
+    bg: DEF_BG,
+    bold: false,
+    italic: false,
+    underline: false,
+  };
+
+  fn of(cell: &vt100::Cell, palette: &Palette) -> Self {
+    let fg = palette.color(cell.fgcolor()).unwrap_or(DEF_FG);
+    let bg = palette.color(cell.bgcolor()).unwrap_or(DEF_BG);
+    let (fg, bg) = if cell.inverse() { (bg, fg) } else { (fg, bg) };
+    Style {
+      fg,
+      bg,
+      bold: cell.bold(),
+      italic: cell.italic(),
+      underline: cell.underline(),
+    }
+  }
+
+  fn css(&self) -> String {
+    let [r, g, b] = self.fg;
+    let mut css = format!("color:#{:02x}{:02x}{:02x}", r, g, b);
+    if self.bg != DEF_BG {
+      let [r, g, b] = self.bg;
+      let _ = write!(css, ";background:#{:02x}{:02x}{:02x}", r, g, b);
+    }
+    if self.bold {
+      css.push_str(";font-weight:bold");
+    }
+    if self.italic {
+      css.push_str(";font-style:italic");
+    }
+    if self.underline {
+      css.push_str(";text-decoration:underline");
+    }
+    css
+  }
+}
+
+/// The screen as a `<pre>` of one span per run of cells with the same style.
+/// Cells with the default style aren't wrapped into spans and blank cells at
+/// the end of rows are left out.
+pub fn to_html(screen: &vt100::Screen, palette: &Palette) -> String {
+  let [r, g, b] = DEF_BG;
+  let bg = format!("#{:02x}{:02x}{:02x}", r, g, b);
+  let [r, g, b] = DEF_FG;
+  let fg = format!("#{:02x}{:02x}{:02x}", r, g, b);
+  let mut html = format!(
+    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+     <title>virterm</title>\n</head>\n<body>\n\
+     <pre style=\"background:{};color:{};padding:8px\">",
+    bg, fg
+  );
+
+  for row in 0..screen.size().0 {
+    if row > 0 {
+      html.push('\n');
+    }
+    let runs = style_runs(screen, row, &Style::DEFAULT, |cell| {
+      Style::of(cell, palette)
+    });
+    for (style, text) in &runs {
+      if *style == Style::DEFAULT {
+        push_escaped(&mut html, text);
+      } else {
+        let _ = write!(html, "<span style=\"{}\">", style.css());
+        push_escaped(&mut html, text);
+        html.push_str("</span>");
+      }
+    }
+  }
+
+  html.push_str("</pre>\n</body>\n</html>\n");
+  html
+}
+
+fn push_escaped(html: &mut String, text: &str) {
+  for ch in text.chars() {
+    match ch {
+      '&' => html.push_str("&amp;"),
+      '<' => html.push_str("&lt;"),
+      '>' => html.push_str("&gt;"),
+      '"' => html.push_str("&quot;"),
+      ch => html.push(ch),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn to_html() {
+    let mut parser = vt100::Parser::new(2, 12, 0);
+    parser.process(
+      b"a<b \x1b[1;31mred\x1b[0m \x1b[7minv\x1b[0m\r\n\
+        \x1b[4;38;2;1;2;3;48;5;4mx\x1b[0m &",
+    );
+    let html = super::to_html(parser.screen(), &Palette::default());
+    let pre = html
+      .split_once("padding:8px\">")
+      .and_then(|(_, rest)| rest.split_once("</pre>"))
+      .unwrap()
+      .0;
+    assert_eq!(
+      pre,
+      "a&lt;b <span style=\"color:#cd0000;font-weight:bold\">red</span> \
+       <span style=\"color:#0a0a32;background:#f0f0f0\">inv</span>\n\
+       <span style=\"color:#010203;background:#0000ee;\
+       text-decoration:underline\">x</span> &amp;"
+    );
+    assert!(html.starts_with("<!DOCTYPE html>\n"));
+    assert!(html.ends_with("</html>\n"));
+  }
+}
@@ -0,0 +1,67 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::fs_utils::write_atomic;
+
+/// Writes the screen size, the cursor and the text of the rows as json.
+pub fn dump_json(screen: &vt100::Screen, path: &str) -> Result<()> {
+  let json = screen_json(screen);
+  write_atomic(path, |tmp| {
+    std::fs::write(tmp, json)?;
+    Ok(())
+  })
+}
+
+fn screen_json(screen: &vt100::Screen) -> String {
+  let (rows, cols) = screen.size();
+  let (cursor_row, cursor_col) = screen.cursor_position();
+  let mut json = String::new();
+  let _ = write!(
+    json,
+    "{{\"rows\":{},\"cols\":{},\"cursor\":{{\"x\":{},\"y\":{}}},\"lines\":[",
+    rows, cols, cursor_col, cursor_row
+  );
+  for (i, line) in screen.rows(0, cols).enumerate() {
+    if i > 0 {
+      json.push(',');
+    }
+    write_json_str(&mut json, &line);
+  }
+  json.push_str("]}\n");
+  json
+}
+
+pub fn write_json_str(json: &mut String, s: &str) {
+  json.push('"');
+  for ch in s.chars() {
+    match ch {
+      '"' => json.push_str("\\\""),
+      '\\' => json.push_str("\\\\"),
+      '\n' => json.push_str("\\n"),
+      '\r' => json.push_str("\\r"),
+      '\t' => json.push_str("\\t"),
+      ch if (ch as u32) < 0x20 => {
+        let _ = write!(json, "\\u{:04x}", ch as u32);
+      }
+      ch => json.push(ch),
+    }
+  }
+  json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn json() {
+    let mut parser = vt100::Parser::new(2, 10, 0);
+    parser.process(b"say \"hi\"\r\n\\o/");
+    assert_eq!(
+      screen_json(parser.screen()),
+      "{\"rows\":2,\"cols\":10,\"cursor\":{\"x\":3,\"y\":1},\
+       \"lines\":[\"say \\\"hi\\\"\",\"\\\\o/\"]}\n"
+    );
+  }
+}
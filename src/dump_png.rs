@@ -1,108 +1,618 @@
-use ab_glyph::ScaleFont;
-use anyhow::Result;
-use image::Rgb;
-
-pub fn dump_png(screen: &vt100::Screen, path: &str) -> Result<()> {
-  let px = 43.0;
-  let (h, w) = screen.size();
-  let w = w as u32;
-  let h = h as u32;
-
-  let fonts = {
-    let regular = include_bytes!("fonts/JetBrainsMono-Regular.ttf") as &[u8];
-    let bold = include_bytes!("fonts/JetBrainsMono-Bold.ttf") as &[u8];
-    let italic = include_bytes!("fonts/JetBrainsMono-Italic.ttf") as &[u8];
-    let bold_italic =
-      include_bytes!("fonts/JetBrainsMono-BoldItalic.ttf") as &[u8];
-
-    let fonts = [regular, bold, italic, bold_italic];
-    let fonts = fonts.map(|font| {
-      let font = ab_glyph::FontRef::try_from_slice(font).unwrap();
-      let font = ab_glyph::Font::into_scaled(font, px);
-      font
-    });
-
-    fonts
+use ab_glyph::{FontArc, PxScaleFont, ScaleFont};
+use anyhow::{bail, Context, Result};
+use image::{Rgb, RgbImage};
+use serde::Deserialize;
+
+use crate::{
+  dump_txt::{is_unprintable, Unprintable},
+  fs_utils::write_atomic,
+  osc::parse_color,
+};
+
+pub const DEF_BG: [u8; 3] = [10, 10, 50];
+pub const DEF_FG: [u8; 3] = [240, 240, 240];
+/// The band of labels is the background tinted with the foreground.
+const LABEL_ALPHA: f32 = 0.15;
+const GUTTER: [u8; 3] = [0, 0, 0];
+/// Overlay of the changed cells in diff images.
+const DIFF_TINT: [u8; 3] = [255, 0, 0];
+const DIFF_ALPHA: f32 = 0.4;
+
+pub fn dump_png(
+  screen: &vt100::Screen,
+  palette: &Palette,
+  path: &str,
+) -> Result<()> {
+  let mut renderer = Renderer::new()?;
+  renderer.set_palette(palette);
+  save(&renderer.render(screen), path)
+}
+
+/// Saves the image atomically, in the format picked by the path extension.
+pub fn save(canvas: &RgbImage, path: &str) -> Result<()> {
+  write_atomic(path, |tmp| {
+    canvas.save(tmp)?;
+    Ok(())
+  })
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ComposeOpts {
+  #[serde(default)]
+  pub layout: Layout,
+  /// Labels rendered above the panels, in the same order as the screens.
+  #[serde(default)]
+  pub labels: Vec<String>,
+  /// Space between panels in pixels.
+  pub gutter: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub enum Layout {
+  #[default]
+  #[serde(rename = "horizontal")]
+  Horizontal,
+  #[serde(rename = "vertical")]
+  Vertical,
+}
+
+/// Stitches panels rendered by `Renderer::render_panel()` into one image.
+pub fn compose(panels: &[RgbImage], opts: &ComposeOpts) -> RgbImage {
+  let gutter = opts.gutter.unwrap_or(20);
+  let gutters = gutter * panels.len().saturating_sub(1) as u32;
+  let (w, h) = match opts.layout {
+    Layout::Horizontal => (
+      panels.iter().map(|p| p.width()).sum::<u32>() + gutters,
+      panels.iter().map(|p| p.height()).max().unwrap_or(0),
+    ),
+    Layout::Vertical => (
+      panels.iter().map(|p| p.width()).max().unwrap_or(0),
+      panels.iter().map(|p| p.height()).sum::<u32>() + gutters,
+    ),
   };
 
-  let canon = fonts[0].scaled_glyph('a');
-  let canon_b = fonts[0].glyph_bounds(&canon);
-  let ch_w = canon_b.max.x.round() as u32;
-  let ch_h = fonts[0].height().round() as u32;
+  let mut canvas = RgbImage::from_pixel(w, h, Rgb(GUTTER));
+  let mut offset = 0;
+  for panel in panels {
+    let (x, y) = match opts.layout {
+      Layout::Horizontal => (offset, 0),
+      Layout::Vertical => (0, offset),
+    };
+    image::imageops::replace(&mut canvas, panel, x as i64, y as i64);
+    offset += gutter
+      + match opts.layout {
+        Layout::Horizontal => panel.width(),
+        Layout::Vertical => panel.height(),
+      };
+  }
+  canvas
+}
 
-  let mut canvas = image::RgbImage::new(w * ch_w, h * ch_h);
+/// Font size in pixels of the default cell size.
+const FONT_PX: f32 = 43.0;
 
-  fn vt_color_to_rgb(from: vt100::Color) -> Option<[u8; 3]> {
-    let color = match from {
-      vt100::Color::Default => return None,
-      vt100::Color::Idx(idx) => {
-        let (r, g, b) = ansi_colours::rgb_from_ansi256(idx);
-        [r, g, b]
+/// Font files of the regular, bold, italic and bold italic styles. Styles
+/// without a file use the bundled fonts.
+#[derive(Debug, Default, Deserialize)]
+pub struct FontFiles {
+  pub regular: Option<String>,
+  pub bold: Option<String>,
+  pub italic: Option<String>,
+  pub bold_italic: Option<String>,
+}
+
+/// Colors of the terminal, e.g. of the theme of a real terminal. Colors are
+/// `#rrggbb` or `rgb:rr/gg/bb`, like in OSC 4.
+#[derive(Debug, Default, Deserialize)]
+pub struct Theme {
+  /// Default foreground and background.
+  pub fg: Option<String>,
+  pub bg: Option<String>,
+  /// The 16 ANSI colors or the whole 256 color palette.
+  #[serde(default)]
+  pub palette: Vec<String>,
+}
+
+/// Renders terminal screens with the bundled fonts.
+pub struct Renderer {
+  /// Unscaled fonts, in the order of `fonts`.
+  faces: [FontArc; 4],
+  fonts: [PxScaleFont<FontArc>; 4],
+  /// Font size in pixels that the cell size is derived from.
+  font_px: f32,
+  ch_w: u32,
+  ch_h: u32,
+  /// Offset of glyphs in cells, when the cell size is set with
+  /// `set_cell_size()`.
+  glyph_x: u32,
+  glyph_y: u32,
+  unprintable: Option<Unprintable>,
+  palette: Palette,
+  /// Colors of the theme, used unless the process redefined them.
+  fg: [u8; 3],
+  bg: [u8; 3],
+  colors: [[u8; 3]; 256],
+}
+
+impl Renderer {
+  pub fn new() -> Result<Self> {
+    let faces = load_bundled(BUNDLED_FONTS)?;
+    let fonts = scale_fonts(&faces, FONT_PX);
+    let (ch_w, ch_h) = cell_size(&fonts);
+
+    Ok(Renderer {
+      faces,
+      fonts,
+      font_px: FONT_PX,
+      ch_w,
+      ch_h,
+      glyph_x: 0,
+      glyph_y: 0,
+      unprintable: None,
+      palette: Palette::default(),
+      fg: DEF_FG,
+      bg: DEF_BG,
+      colors: std::array::from_fn(|idx| palette_rgb(idx as u8)),
+    })
+  }
+
+  /// Renders with the fonts of `files`. The cell size is derived from the
+  /// regular font, so this goes before `set_cell_size()`.
+  pub fn set_fonts(&mut self, files: &FontFiles) -> Result<()> {
+    let paths = [
+      &files.regular,
+      &files.bold,
+      &files.italic,
+      &files.bold_italic,
+    ];
+    for (face, path) in self.faces.iter_mut().zip(paths) {
+      if let Some(path) = path {
+        let bytes = std::fs::read(path)
+          .with_context(|| format!("Failed to read font: {}", path))?;
+        *face = parse_font(path, bytes)?;
       }
-      vt100::Color::Rgb(r, g, b) => [r, g, b],
+    }
+    self.fonts = scale_fonts(&self.faces, self.font_px);
+    (self.ch_w, self.ch_h) = cell_size(&self.fonts);
+    (self.glyph_x, self.glyph_y) = (0, 0);
+    Ok(())
+  }
+
+  /// Sets the font size in pixels, the cell size is derived from it. Goes
+  /// before `set_cell_size()`, which scales the font again.
+  pub fn set_font_px(&mut self, px: f32) -> Result<()> {
+    if !(px.is_finite() && px > 0.0) {
+      bail!("Font size must be positive, got {}", px);
+    }
+    self.font_px = px;
+    self.fonts = scale_fonts(&self.faces, px);
+    (self.ch_w, self.ch_h) = cell_size(&self.fonts);
+    (self.glyph_x, self.glyph_y) = (0, 0);
+    Ok(())
+  }
+
+  /// Sets the cell size in pixels instead of the one of the font metrics, a
+  /// `None` dimension keeps the metric one. The font is scaled to fit into
+  /// the cells and glyphs are centered in them.
+  pub fn set_cell_size(
+    &mut self,
+    width: Option<u32>,
+    height: Option<u32>,
+  ) -> Result<()> {
+    if width == Some(0) || height == Some(0) {
+      bail!("Cell size must be positive");
+    }
+    if width.is_none() && height.is_none() {
+      return Ok(());
+    }
+    let (metric_w, metric_h) =
+      cell_size(&scale_fonts(&self.faces, self.font_px));
+    let (ch_w, ch_h) = (width.unwrap_or(metric_w), height.unwrap_or(metric_h));
+    let scale =
+      f32::min(ch_w as f32 / metric_w as f32, ch_h as f32 / metric_h as f32);
+    self.fonts = scale_fonts(&self.faces, self.font_px * scale);
+    let (font_w, font_h) = cell_size(&self.fonts);
+    self.ch_w = ch_w;
+    self.ch_h = ch_h;
+    self.glyph_x = ch_w.saturating_sub(font_w) / 2;
+    self.glyph_y = ch_h.saturating_sub(font_h) / 2;
+    Ok(())
+  }
+
+  /// Renders with the colors of `theme`. Colors it doesn't set keep the
+  /// defaults, colors redefined by the process override the theme.
+  pub fn set_theme(&mut self, theme: &Theme) -> Result<()> {
+    let color = |spec: &str| {
+      parse_color(spec)
+        .with_context(|| format!("Invalid theme color: {:?}", spec))
     };
-    Some(color)
+    if !matches!(theme.palette.len(), 0 | 16 | 256) {
+      bail!(
+        "Theme palette must have 16 or 256 colors, got {}",
+        theme.palette.len()
+      );
+    }
+    if let Some(fg) = &theme.fg {
+      self.fg = color(fg)?;
+    }
+    if let Some(bg) = &theme.bg {
+      self.bg = color(bg)?;
+    }
+    for (rgb, spec) in self.colors.iter_mut().zip(&theme.palette) {
+      *rgb = color(spec)?;
+    }
+    Ok(())
   }
 
-  let def_bg = [10, 10, 50];
-  let def_fg = [240, 240, 240];
+  /// Palette of indexed colors, as redefined by the process.
+  pub fn set_palette(&mut self, palette: &Palette) {
+    self.palette = palette.clone();
+  }
 
-  for row in 0..h {
-    for col in 0..w {
-      let cell = screen.cell(row as u16, col as u16).unwrap();
-      let fg = vt_color_to_rgb(cell.fgcolor()).unwrap_or(def_fg);
-      let bg = vt_color_to_rgb(cell.bgcolor()).unwrap_or(def_bg);
+  /// Marks cells with invisible chars. They are rendered as blanks by
+  /// default, like on a real terminal.
+  pub fn set_unprintable(&mut self, unprintable: Option<Unprintable>) {
+    self.unprintable = unprintable;
+  }
 
-      let x0 = col * ch_w;
-      let y0 = row * ch_h;
-      for y in y0..(y0 + ch_h) {
-        for x in x0..(x0 + ch_w) {
-          canvas.put_pixel(x, y, Rgb(bg));
+  pub fn render(&self, screen: &vt100::Screen) -> RgbImage {
+    let (rows, cols) = screen.size();
+    self.render_cells(rows, cols, |row, col| screen.cell(row, col))
+  }
+
+  /// Renders `screen` with the cells that differ from `baseline` (contents
+  /// or attributes) tinted red. With a different size of the baseline, the
+  /// cells outside of it count as changed.
+  pub fn render_diff(
+    &self,
+    screen: &vt100::Screen,
+    baseline: &vt100::Screen,
+  ) -> RgbImage {
+    let mut canvas = self.render(screen);
+    let (rows, cols) = screen.size();
+    for row in 0..rows {
+      for col in 0..cols {
+        if screen.cell(row, col) == baseline.cell(row, col) {
+          continue;
+        }
+        let x0 = col as u32 * self.ch_w;
+        let y0 = row as u32 * self.ch_h;
+        for y in y0..y0 + self.ch_h {
+          for x in x0..x0 + self.ch_w {
+            let pixel = canvas.get_pixel(x, y);
+            let color = blend(DIFF_TINT, pixel.0, DIFF_ALPHA);
+            canvas.put_pixel(x, y, Rgb(color));
+          }
         }
       }
+    }
+    canvas
+  }
+
+  /// Renders rows of cells taken from the screen, e.g. with the scrollback.
+  pub fn render_rows(&self, rows: &[Vec<Option<vt100::Cell>>]) -> RgbImage {
+    let cols = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    self.render_cells(rows.len() as u16, cols as u16, |row, col| {
+      rows[row as usize].get(col as usize)?.as_ref()
+    })
+  }
+
+  fn render_cells<'a, F>(&self, rows: u16, cols: u16, cell: F) -> RgbImage
+  where
+    F: Fn(u16, u16) -> Option<&'a vt100::Cell>,
+  {
+    let w = cols as u32;
+    let h = rows as u32;
+    let (ch_w, ch_h) = (self.ch_w, self.ch_h);
+
+    let mut canvas = RgbImage::new(w * ch_w, h * ch_h);
 
-      if let Some(ch) = cell.contents().chars().next() {
-        let font = match (cell.bold(), cell.italic()) {
-          (false, false) => &fonts[0],
-          (true, false) => &fonts[1],
-          (false, true) => &fonts[2],
-          (true, true) => &fonts[3],
+    for row in 0..h {
+      for col in 0..w {
+        // A cell that vt100 doesn't return is rendered as a blank one
+        // instead of failing the whole screenshot.
+        let cell = cell(row as u16, col as u16);
+        let fg = cell
+          .and_then(|cell| self.vt_color_to_rgb(cell.fgcolor()))
+          .unwrap_or(self.fg);
+        let bg = cell
+          .and_then(|cell| self.vt_color_to_rgb(cell.bgcolor()))
+          .unwrap_or(self.bg);
+
+        let x0 = col * ch_w;
+        let y0 = row * ch_h;
+        fill_rect(&mut canvas, x0, y0, ch_w, ch_h, bg);
+
+        let cell = match cell {
+          Some(cell) => cell,
+          None => continue,
         };
-        let glyph = fonts[0].scaled_glyph(ch);
-        let outline = font.outline_glyph(glyph);
-
-        if let Some(outline) = outline {
-          outline.draw(|dx, dy, c| {
-            let x = col * ch_w + dx;
-            let x = x as f32 + outline.px_bounds().min.x;
-            let x = x.round() as u32;
-            let y = row * ch_h + dy;
-            let y = y as f32 + outline.px_bounds().min.y + font.ascent();
-            let y = y.round() as u32;
-
-            if x >= x0 && x < x0 + ch_w && y >= y0 && y < y0 + ch_h {
-              let pixel = canvas.get_pixel(x, y);
-              let pixel = pixel.0.map(|x| x as f32);
-              let color = fg.map(|x| x as f32);
-
-              let color = color
-                .into_iter()
-                .zip(pixel)
-                .map(|(top, bot)| (top * c + bot * (1.0 - c)) as u8)
-                .collect::<Vec<_>>();
-              let color = [color[0], color[1], color[2]];
-
-              canvas.put_pixel(x, y, Rgb(color));
+
+        let contents = cell.contents();
+        let mut ch = contents.chars().next();
+        if contents.chars().any(is_unprintable) {
+          match self.unprintable {
+            Some(Unprintable::Box) => {
+              draw_frame(&mut canvas, x0, y0, ch_w, ch_h, fg);
             }
-          });
+            Some(Unprintable::Char(marker)) => ch = Some(marker),
+            None => (),
+          }
         }
+        if let Some(ch) = ch {
+          let font = match (cell.bold(), cell.italic()) {
+            (false, false) => 0,
+            (true, false) => 1,
+            (false, true) => 2,
+            (true, true) => 3,
+          };
+          self.draw_char(&mut canvas, x0, y0, ch, font, fg);
+        }
+      }
+    }
+
+    canvas
+  }
+
+  /// Renders a screen with an optional label band above it.
+  pub fn render_panel(
+    &self,
+    screen: &vt100::Screen,
+    label: Option<&str>,
+  ) -> RgbImage {
+    let screen = self.render(screen);
+    match label {
+      Some(label) => self.add_label(&screen, label),
+      None => screen,
+    }
+  }
+
+  /// Adds a band with `label` above the image, in the default colors of the
+  /// theme. The band is one row of cells high, a label longer than the image
+  /// is cut.
+  pub fn add_label(&self, image: &RgbImage, label: &str) -> RgbImage {
+    let mut canvas = RgbImage::new(image.width(), image.height() + self.ch_h);
+    let bg = blend(self.fg, self.bg, LABEL_ALPHA);
+    fill_rect(&mut canvas, 0, 0, image.width(), self.ch_h, bg);
+    self.draw_text(&mut canvas, 0, 0, label, self.fg);
+    image::imageops::replace(&mut canvas, image, 0, self.ch_h as i64);
+    canvas
+  }
+
+  /// Draws a line of text in cells starting at pixel `x0`:`y0`. Text that
+  /// doesn't fit into the canvas is cut.
+  fn draw_text(
+    &self,
+    canvas: &mut RgbImage,
+    x0: u32,
+    y0: u32,
+    text: &str,
+    fg: [u8; 3],
+  ) {
+    for (i, ch) in text.chars().enumerate() {
+      let x = x0 + i as u32 * self.ch_w;
+      if x + self.ch_w > canvas.width() {
+        break;
       }
+      self.draw_char(canvas, x, y0, ch, 0, fg);
+    }
+  }
+
+  fn vt_color_to_rgb(&self, from: vt100::Color) -> Option<[u8; 3]> {
+    let color = match from {
+      vt100::Color::Default => return None,
+      vt100::Color::Idx(idx) => self
+        .palette
+        .redefined(idx)
+        .unwrap_or(self.colors[idx as usize]),
+      vt100::Color::Rgb(r, g, b) => [r, g, b],
+    };
+    Some(color)
+  }
+
+  /// Draws a glyph into the cell at pixel `x0`:`y0`.
+  fn draw_char(
+    &self,
+    canvas: &mut RgbImage,
+    x0: u32,
+    y0: u32,
+    ch: char,
+    font: usize,
+    fg: [u8; 3],
+  ) {
+    let (ch_w, ch_h) = (self.ch_w, self.ch_h);
+    let font = &self.fonts[font];
+    let glyph = font.scaled_glyph(ch);
+    let outline = font.outline_glyph(glyph);
+
+    if let Some(outline) = outline {
+      outline.draw(|dx, dy, c| {
+        let x = x0 + self.glyph_x + dx;
+        let x = x as f32 + outline.px_bounds().min.x;
+        let x = x.round() as u32;
+        let y = y0 + self.glyph_y + dy;
+        let y = y as f32 + outline.px_bounds().min.y + font.ascent();
+        let y = y.round() as u32;
+
+        if x >= x0 && x < x0 + ch_w && y >= y0 && y < y0 + ch_h {
+          let pixel = canvas.get_pixel(x, y);
+          let color = blend(fg, pixel.0, c);
+          canvas.put_pixel(x, y, Rgb(color));
+        }
+      });
+    }
+  }
+}
+
+type FontFile = (&'static str, &'static [u8]);
+
+/// Regular, bold, italic and bold italic fonts embedded into virterm.
+const BUNDLED_FONTS: [FontFile; 4] = [
+  (
+    "JetBrainsMono-Regular.ttf",
+    include_bytes!("fonts/JetBrainsMono-Regular.ttf"),
+  ),
+  (
+    "JetBrainsMono-Bold.ttf",
+    include_bytes!("fonts/JetBrainsMono-Bold.ttf"),
+  ),
+  (
+    "JetBrainsMono-Italic.ttf",
+    include_bytes!("fonts/JetBrainsMono-Italic.ttf"),
+  ),
+  (
+    "JetBrainsMono-BoldItalic.ttf",
+    include_bytes!("fonts/JetBrainsMono-BoldItalic.ttf"),
+  ),
+];
+
+/// Loads the bundled fonts. A style that fails to load is rendered with the
+/// regular font, only a broken regular font is an error.
+fn load_bundled(files: [FontFile; 4]) -> Result<[FontArc; 4]> {
+  let [(name, bytes), ..] = files;
+  let regular = FontArc::try_from_slice(bytes)
+    .with_context(|| format!("Invalid bundled font: {}", name))?;
+  Ok(files.map(|(name, bytes)| {
+    FontArc::try_from_slice(bytes).unwrap_or_else(|err| {
+      log::warn!("Invalid bundled font: {}: {}", name, err);
+      regular.clone()
+    })
+  }))
+}
+
+fn parse_font(path: &str, bytes: Vec<u8>) -> Result<FontArc> {
+  FontArc::try_from_vec(bytes)
+    .with_context(|| format!("Invalid font: {}", path))
+}
+
+fn scale_fonts(faces: &[FontArc; 4], px: f32) -> [PxScaleFont<FontArc>; 4] {
+  faces
+    .clone()
+    .map(|face| ab_glyph::Font::into_scaled(face, px))
+}
+
+/// Cell size in pixels derived from the font metrics.
+/// Cells are at least 1 pixel wide and high, even for tiny fonts.
+fn cell_size(fonts: &[PxScaleFont<FontArc>; 4]) -> (u32, u32) {
+  let canon = fonts[0].scaled_glyph('a');
+  let canon_b = fonts[0].glyph_bounds(&canon);
+  let ch_w = canon_b.max.x.round() as u32;
+  let ch_h = fonts[0].height().round() as u32;
+  (ch_w.max(1), ch_h.max(1))
+}
+
+/// One pixel wide outline of a rectangle.
+fn draw_frame(
+  canvas: &mut RgbImage,
+  x0: u32,
+  y0: u32,
+  w: u32,
+  h: u32,
+  color: [u8; 3],
+) {
+  fill_rect(canvas, x0, y0, w, 1, color);
+  fill_rect(canvas, x0, y0 + h - 1, w, 1, color);
+  fill_rect(canvas, x0, y0, 1, h, color);
+  fill_rect(canvas, x0 + w - 1, y0, 1, h, color);
+}
+
+/// Color of the 256 color palette.
+pub fn palette_rgb(idx: u8) -> [u8; 3] {
+  let (r, g, b) = ansi_colours::rgb_from_ansi256(idx);
+  [r, g, b]
+}
+
+/// The 256 color palette with the colors redefined by the process (OSC 4).
+/// Other colors are the standard ones of `palette_rgb()`.
+#[derive(Clone)]
+pub struct Palette {
+  colors: [Option<[u8; 3]>; 256],
+}
+
+impl Default for Palette {
+  fn default() -> Self {
+    Palette {
+      colors: [None; 256],
+    }
+  }
+}
+
+impl Palette {
+  pub fn rgb(&self, idx: u8) -> [u8; 3] {
+    self.redefined(idx).unwrap_or_else(|| palette_rgb(idx))
+  }
+
+  /// RGB of a cell color, `None` for the default color.
+  pub fn color(&self, color: vt100::Color) -> Option<[u8; 3]> {
+    match color {
+      vt100::Color::Default => None,
+      vt100::Color::Idx(idx) => Some(self.rgb(idx)),
+      vt100::Color::Rgb(r, g, b) => Some([r, g, b]),
     }
   }
 
-  canvas.save(path)?;
+  /// The color of `idx` if the process redefined it.
+  pub fn redefined(&self, idx: u8) -> Option<[u8; 3]> {
+    self.colors[idx as usize]
+  }
+
+  pub fn set(&mut self, idx: u8, rgb: [u8; 3]) {
+    self.colors[idx as usize] = Some(rgb);
+  }
 
-  Ok(())
+  /// Restores the standard color of `idx`, or of all colors.
+  pub fn reset(&mut self, idx: Option<u8>) {
+    match idx {
+      Some(idx) => self.colors[idx as usize] = None,
+      None => self.colors = [None; 256],
+    }
+  }
+}
+
+fn fill_rect(
+  canvas: &mut RgbImage,
+  x0: u32,
+  y0: u32,
+  w: u32,
+  h: u32,
+  color: [u8; 3],
+) {
+  for y in y0..(y0 + h) {
+    for x in x0..(x0 + w) {
+      canvas.put_pixel(x, y, Rgb(color));
+    }
+  }
+}
+
+/// Blends `top` over `bot` with `alpha` coverage. Blending is done in linear
+/// space, so antialiased edges keep the right brightness.
+fn blend(top: [u8; 3], bot: [u8; 3], alpha: f32) -> [u8; 3] {
+  let alpha = alpha.clamp(0.0, 1.0);
+  let mut out = [0; 3];
+  for i in 0..3 {
+    let top = srgb_to_linear(top[i]);
+    let bot = srgb_to_linear(bot[i]);
+    out[i] = linear_to_srgb(top * alpha + bot * (1.0 - alpha));
+  }
+  out
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+  let c = c as f32 / 255.0;
+  if c <= 0.04045 {
+    c / 12.92
+  } else {
+    ((c + 0.055) / 1.055).powf(2.4)
+  }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+  let c = if c <= 0.0031308 {
+    c * 12.92
+  } else {
+    1.055 * c.powf(1.0 / 2.4) - 0.055
+  };
+  (c * 255.0).round().clamp(0.0, 255.0) as u8
 }
 
 #[allow(dead_code)]
@@ -136,3 +646,317 @@ fn debug_font_metrics(font: &ab_glyph::PxScaleFont<&ab_glyph::FontRef>) {
   }
   println!("----");
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_close(a: [u8; 3], b: [u8; 3], tolerance: u8) {
+    for i in 0..3 {
+      assert!(a[i].abs_diff(b[i]) <= tolerance, "{:?} != {:?}", a, b);
+    }
+  }
+
+  #[test]
+  fn blend_linear() {
+    assert_eq!(blend([255, 0, 30], [0, 255, 40], 1.0), [255, 0, 30]);
+    assert_eq!(blend([255, 0, 30], [0, 255, 40], 0.0), [0, 255, 40]);
+    // Half coverage of white over black is linear 0.5, not sRGB 127.
+    assert_close(blend([255; 3], [0; 3], 0.5), [188; 3], 1);
+    // Rounds instead of truncating.
+    for c in 0..=255 {
+      assert_eq!(linear_to_srgb(srgb_to_linear(c)), c);
+    }
+  }
+
+  #[test]
+  fn compose_layouts() {
+    let a = RgbImage::from_pixel(4, 2, Rgb([1, 1, 1]));
+    let b = RgbImage::from_pixel(3, 5, Rgb([2, 2, 2]));
+    let panels = [a, b];
+
+    let opts = ComposeOpts {
+      gutter: Some(2),
+      ..Default::default()
+    };
+    let canvas = compose(&panels, &opts);
+    assert_eq!(canvas.dimensions(), (9, 5));
+    assert_eq!(canvas.get_pixel(0, 0).0, [1, 1, 1]);
+    assert_eq!(canvas.get_pixel(4, 0).0, GUTTER);
+    assert_eq!(canvas.get_pixel(6, 4).0, [2, 2, 2]);
+
+    let opts = ComposeOpts {
+      layout: Layout::Vertical,
+      gutter: Some(2),
+      ..Default::default()
+    };
+    let canvas = compose(&panels, &opts);
+    assert_eq!(canvas.dimensions(), (4, 9));
+    assert_eq!(canvas.get_pixel(0, 3).0, GUTTER);
+    assert_eq!(canvas.get_pixel(0, 4).0, [2, 2, 2]);
+  }
+
+  #[test]
+  fn truecolor_gradient() {
+    let width = 16;
+    let mut parser = vt100::Parser::new(1, width, 0);
+    for i in 0..width {
+      let v = i * 17;
+      parser
+        .process(format!("\x1b[48;2;{};{};{}m ", v, 255 - v, 128).as_bytes());
+    }
+    let canvas = Renderer::new().unwrap().render(parser.screen());
+
+    let ch_w = canvas.width() / width as u32;
+    let ch_h = canvas.height();
+    for i in 0..width {
+      let v = (i * 17) as u8;
+      let x = i as u32 * ch_w + ch_w / 2;
+      let pixel = canvas.get_pixel(x, ch_h / 2).0;
+      assert_close(pixel, [v, 255 - v, 128], 1);
+    }
+  }
+
+  #[test]
+  fn render_is_deterministic() {
+    let mut parser = vt100::Parser::new(2, 10, 0);
+    parser.process("\x1b[1;31mhello\x1b[0m\r\n\x1b[3m日本".as_bytes());
+    let a = Renderer::new().unwrap().render(parser.screen());
+    let b = Renderer::new().unwrap().render(parser.screen());
+    assert!(a.as_raw() == b.as_raw());
+  }
+
+  #[test]
+  fn label() {
+    let mut parser = vt100::Parser::new(2, 10, 0);
+    parser.process(b"hello");
+    let renderer = Renderer::new().unwrap();
+    let screen = renderer.render(parser.screen());
+    let canvas = renderer.add_label(&screen, "Step 1: login and more");
+    assert_eq!(canvas.width(), screen.width());
+    assert_eq!(canvas.height(), screen.height() + renderer.ch_h);
+    assert_eq!(canvas.get_pixel(0, 0).0, blend(DEF_FG, DEF_BG, LABEL_ALPHA));
+    let below = image::imageops::crop_imm(
+      &canvas,
+      0,
+      renderer.ch_h,
+      screen.width(),
+      screen.height(),
+    );
+    assert!(below.to_image().as_raw() == screen.as_raw());
+  }
+
+  #[test]
+  fn label_theme() {
+    let mut renderer = Renderer::new().unwrap();
+    let theme = Theme {
+      fg: Some("#002b36".to_string()),
+      bg: Some("#fdf6e3".to_string()),
+      ..Default::default()
+    };
+    renderer.set_theme(&theme).unwrap();
+    let screen = RgbImage::new(10 * renderer.ch_w, renderer.ch_h);
+    let canvas = renderer.add_label(&screen, "MMMMMMMMMM");
+    let band = (0..canvas.width())
+      .flat_map(|x| (0..renderer.ch_h).map(move |y| (x, y)))
+      .map(|(x, y)| canvas.get_pixel(x, y).0)
+      .collect::<Vec<_>>();
+    let label_bg = blend([0x00, 0x2b, 0x36], [0xfd, 0xf6, 0xe3], LABEL_ALPHA);
+    assert_eq!(band[0], label_bg);
+    assert!(band.contains(&[0x00, 0x2b, 0x36]));
+    assert!(band.iter().all(|&pixel| pixel != DEF_FG && pixel != DEF_BG));
+  }
+
+  #[test]
+  fn cell_size() {
+    let mut parser = vt100::Parser::new(2, 3, 0);
+    parser.process(b"M");
+    let mut renderer = Renderer::new().unwrap();
+    renderer.set_cell_size(Some(10), Some(20)).unwrap();
+    let canvas = renderer.render(parser.screen());
+    assert_eq!(canvas.dimensions(), (30, 40));
+    // The glyph is drawn into the first cell.
+    let drawn = (0..10)
+      .flat_map(|x| (0..20).map(move |y| (x, y)))
+      .any(|(x, y)| canvas.get_pixel(x, y).0 != DEF_BG);
+    assert!(drawn);
+
+    let mut renderer = Renderer::new().unwrap();
+    let metric_w = renderer.ch_w;
+    renderer.set_cell_size(None, Some(20)).unwrap();
+    assert_eq!((renderer.ch_w, renderer.ch_h), (metric_w, 20));
+    assert!(renderer.set_cell_size(Some(0), None).is_err());
+  }
+
+  #[test]
+  fn set_theme() {
+    let mut parser = vt100::Parser::new(1, 3, 0);
+    parser.process(b"\x1b[31;42m \x1b[0m \x1b]4;1;#000001\x07\x1b[41m ");
+    let mut renderer = Renderer::new().unwrap();
+    let mut palette = vec!["#000000".to_string(); 16];
+    palette[2] = "#859900".to_string();
+    let theme = Theme {
+      bg: Some("#002b36".to_string()),
+      palette,
+      ..Default::default()
+    };
+    renderer.set_theme(&theme).unwrap();
+    let mut palette = Palette::default();
+    palette.set(1, [0, 0, 1]);
+    renderer.set_palette(&palette);
+    let canvas = renderer.render(parser.screen());
+    let bg = |col: u32| canvas.get_pixel(col * renderer.ch_w, 0).0;
+    assert_eq!(bg(0), [0x85, 0x99, 0x00]);
+    assert_eq!(bg(1), [0x00, 0x2b, 0x36]);
+    // The process redefined the color.
+    assert_eq!(bg(2), [0, 0, 1]);
+
+    let mut err =
+      |theme: Theme| format!("{:#}", renderer.set_theme(&theme).err().unwrap());
+    let palette = vec!["#000".to_string(); 8];
+    assert_eq!(
+      err(Theme {
+        palette,
+        ..Default::default()
+      }),
+      "Theme palette must have 16 or 256 colors, got 8"
+    );
+    assert_eq!(
+      err(Theme {
+        fg: Some("red".to_string()),
+        ..Default::default()
+      }),
+      "Invalid theme color: \"red\""
+    );
+  }
+
+  #[test]
+  fn set_font_px() {
+    let mut parser = vt100::Parser::new(2, 3, 0);
+    parser.process(b"M");
+    let mut renderer = Renderer::new().unwrap();
+    let (ch_w, ch_h) = (renderer.ch_w, renderer.ch_h);
+    renderer.set_font_px(FONT_PX / 2.0).unwrap();
+    assert!(renderer.ch_w.abs_diff(ch_w / 2) <= 1);
+    assert!(renderer.ch_h.abs_diff(ch_h / 2) <= 1);
+    // Tiny fonts still have cells.
+    renderer.set_font_px(0.01).unwrap();
+    assert_eq!((renderer.ch_w, renderer.ch_h), (1, 1));
+    assert_eq!(renderer.render(parser.screen()).dimensions(), (3, 2));
+    assert!(renderer.set_font_px(0.0).is_err());
+    assert!(renderer.set_font_px(f32::NAN).is_err());
+  }
+
+  #[test]
+  fn set_fonts() {
+    let mut renderer = Renderer::new().unwrap();
+    let metrics = (renderer.ch_w, renderer.ch_h);
+    let dir = env!("CARGO_MANIFEST_DIR");
+    let files = FontFiles {
+      regular: Some(format!("{}/src/fonts/JetBrainsMono-Bold.ttf", dir)),
+      ..Default::default()
+    };
+    renderer.set_fonts(&files).unwrap();
+    assert_eq!((renderer.ch_w, renderer.ch_h), metrics);
+
+    let mut err = |regular: &str| {
+      let files = FontFiles {
+        regular: Some(regular.to_string()),
+        ..Default::default()
+      };
+      format!("{:#}", renderer.set_fonts(&files).err().unwrap())
+    };
+    assert!(err("missing.ttf").starts_with("Failed to read font: missing.ttf"));
+    let manifest = format!("{}/Cargo.toml", dir);
+    assert!(err(&manifest).starts_with(&format!("Invalid font: {}", manifest)));
+  }
+
+  #[test]
+  fn styled_glyph_bounds() {
+    let renderer = Renderer::new().unwrap();
+    // Pixel bounds of the drawn glyph, and the ones of the glyph outline of
+    // the style's font.
+    let bounds = |output: &[u8], ch: char, font: usize| {
+      let mut parser = vt100::Parser::new(1, 1, 0);
+      parser.process(output);
+      let canvas = renderer.render(parser.screen());
+      let ink: Vec<(u32, u32)> = canvas
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel.0 != DEF_BG)
+        .map(|(x, y, _)| (x, y))
+        .collect();
+      let drawn = (
+        ink.iter().map(|p| p.0).min().unwrap(),
+        ink.iter().map(|p| p.1).min().unwrap(),
+        ink.iter().map(|p| p.0).max().unwrap(),
+        ink.iter().map(|p| p.1).max().unwrap(),
+      );
+      let font = &renderer.fonts[font];
+      let outline = font.outline_glyph(font.scaled_glyph(ch)).unwrap();
+      let px = outline.px_bounds();
+      let expected = (
+        px.min.x.round() as u32,
+        (px.min.y + font.ascent()).round() as u32,
+        px.max.x.round() as u32 - 1,
+        (px.max.y + font.ascent()).round() as u32 - 1,
+      );
+      (drawn, expected)
+    };
+    let close = |a: (u32, u32, u32, u32), b: (u32, u32, u32, u32)| {
+      [(a.0, b.0), (a.1, b.1), (a.2, b.2), (a.3, b.3)]
+        .iter()
+        .all(|(a, b)| a.abs_diff(*b) <= 1)
+    };
+    let (regular, regular_expected) = bounds(b"W", 'W', 0);
+    let (bold, bold_expected) = bounds(b"\x1b[1mW", 'W', 1);
+    assert!(close(regular, regular_expected), "{:?}", regular);
+    assert!(close(bold, bold_expected), "{:?}", bold);
+    assert_ne!(bold, regular);
+    // The italic font has another glyph id for '@' than the regular one.
+    let (italic, italic_expected) = bounds(b"\x1b[3m@", '@', 2);
+    assert!(close(italic, italic_expected), "{:?}", italic);
+  }
+
+  #[test]
+  fn invalid_fonts() {
+    let err = parse_font("broken.ttf", b"not a font".to_vec())
+      .err()
+      .unwrap();
+    assert_eq!(err.to_string(), "Invalid font: broken.ttf");
+
+    let [regular, _, italic, bold_italic] = BUNDLED_FONTS;
+    let broken = ("broken.ttf", b"not a font" as &[u8]);
+    let fonts = load_bundled([regular, broken, italic, bold_italic]).unwrap();
+    let (_, regular_bytes) = regular;
+    let regular_font = FontArc::try_from_slice(regular_bytes).unwrap();
+    // The broken bold font falls back to the regular one.
+    let bounds = |font: &FontArc| {
+      use ab_glyph::Font;
+      font.outline(font.glyph_id('a')).unwrap().bounds
+    };
+    assert_eq!(bounds(&fonts[1]), bounds(&regular_font));
+    assert_ne!(bounds(&fonts[3]), bounds(&regular_font));
+
+    let err = load_bundled([broken, italic, italic, bold_italic]).err();
+    assert_eq!(err.unwrap().to_string(), "Invalid bundled font: broken.ttf");
+  }
+
+  #[test]
+  fn render_diff() {
+    let mut baseline = vt100::Parser::new(1, 3, 0);
+    baseline.process(b"abc");
+    let mut parser = vt100::Parser::new(1, 4, 0);
+    parser.process(b"a\x1b[1mb\x1b[0mXd");
+
+    let renderer = Renderer::new().unwrap();
+    let canvas = renderer.render_diff(parser.screen(), baseline.screen());
+    let (ch_w, ch_h) = (renderer.ch_w, renderer.ch_h);
+    let bg = |col: u32| canvas.get_pixel(col * ch_w + 1, ch_h - 1).0;
+    let tinted = blend(DIFF_TINT, DEF_BG, DIFF_ALPHA);
+    assert_eq!(bg(0), DEF_BG);
+    // Bold, a different char, outside of the baseline.
+    assert_eq!(bg(1), tinted);
+    assert_eq!(bg(2), tinted);
+    assert_eq!(bg(3), tinted);
+  }
+}
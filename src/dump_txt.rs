@@ -1,7 +1,186 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::Deserialize;
 
-pub fn dump_txt(screen: &vt100::Screen, path: &str) -> Result<()> {
-  std::fs::write(path, screen.contents())?;
+use crate::fs_utils::write_atomic;
 
-  Ok(())
+/// How dumps show characters that are invisible on the screen: control and
+/// zero-width format characters. Set from Lua as `"box"` or a single char.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Unprintable {
+  /// `□` in text, a frame around the cell in images.
+  Box,
+  /// The char instead of the invisible ones.
+  Char(char),
+}
+
+impl TryFrom<String> for Unprintable {
+  type Error = anyhow::Error;
+
+  fn try_from(value: String) -> Result<Self> {
+    let mut chars = value.chars();
+    match (value.as_str(), chars.next(), chars.next()) {
+      ("box", _, _) => Ok(Unprintable::Box),
+      (_, Some(ch), None) => Ok(Unprintable::Char(ch)),
+      _ => bail!("Expected \"box\" or a single char, got: {:?}", value),
+    }
+  }
+}
+
+impl Unprintable {
+  pub fn text_marker(&self) -> char {
+    match self {
+      Unprintable::Box => '□',
+      Unprintable::Char(ch) => *ch,
+    }
+  }
+}
+
+/// Line breaks of text dumps. Set from Lua as `"lf"` or `"crlf"`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+  #[default]
+  Lf,
+  CrLf,
+}
+
+impl LineEnding {
+  /// Converts `\n` line breaks of the screen contents.
+  pub fn apply(&self, contents: String) -> String {
+    match self {
+      LineEnding::Lf => contents,
+      LineEnding::CrLf => contents.replace('\n', "\r\n"),
+    }
+  }
+}
+
+/// Control chars and format chars that take no space, e.g. zero width space.
+/// vt100 keeps zero-width chars in the cell of the preceding char.
+pub fn is_unprintable(ch: char) -> bool {
+  ch.is_control()
+    || matches!(
+      ch,
+      '\u{00AD}'
+        | '\u{200B}'..='\u{200F}'
+        | '\u{202A}'..='\u{202E}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{FEFF}'
+    )
+}
+
+/// Text of a row split into runs of cells with the same `style_of()`, for
+/// exporters that mark up the style. Wide char continuations are skipped,
+/// empty and unprintable cells are spaces and, like in `Screen::contents()`,
+/// blank cells with the `default` style at the end of the row are left out.
+pub fn style_runs<S: PartialEq>(
+  screen: &vt100::Screen,
+  row: u16,
+  default: &S,
+  style_of: impl Fn(&vt100::Cell) -> S,
+) -> Vec<(S, String)> {
+  let mut runs: Vec<(S, String)> = Vec::new();
+  for col in 0..screen.size().1 {
+    let cell = match screen.cell(row, col) {
+      Some(cell) if !cell.is_wide_continuation() => cell,
+      _ => continue,
+    };
+    let style = style_of(cell);
+    let text = match cell.contents() {
+      contents if contents.is_empty() => " ".to_string(),
+      contents if contents.chars().any(is_unprintable) => " ".to_string(),
+      contents => contents,
+    };
+    match runs.last_mut() {
+      Some((last, run_text)) if *last == style => run_text.push_str(&text),
+      _ => runs.push((style, text)),
+    }
+  }
+  if let Some((style, text)) = runs.last_mut() {
+    if style == default {
+      text.truncate(text.trim_end_matches(' ').len());
+    }
+  }
+  runs
+}
+
+pub fn dump_txt(
+  screen: &vt100::Screen,
+  path: &str,
+  unprintable: Option<Unprintable>,
+  line_ending: LineEnding,
+) -> Result<()> {
+  let mut contents = screen.contents();
+  if let Some(unprintable) = unprintable {
+    contents = mark_unprintable(&contents, unprintable.text_marker());
+  }
+  let contents = line_ending.apply(contents);
+  write_atomic(path, |tmp| {
+    std::fs::write(tmp, contents)?;
+    Ok(())
+  })
+}
+
+/// Replaces unprintable chars with `marker`, keeping line breaks.
+fn mark_unprintable(contents: &str, marker: char) -> String {
+  contents
+    .chars()
+    .map(|ch| {
+      if ch != '\n' && is_unprintable(ch) {
+        marker
+      } else {
+        ch
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unprintable() {
+    let mut parser = vt100::Parser::new(2, 20, 0);
+    parser.process("a\u{200b}b\u{feff}\r\nsoft\u{ad}".as_bytes());
+    let contents = parser.screen().contents();
+    assert_eq!(mark_unprintable(&contents, '·'), "a·b·\nsoft·");
+
+    assert_eq!(
+      Unprintable::try_from("box".to_string()).unwrap(),
+      Unprintable::Box
+    );
+    assert_eq!(
+      Unprintable::try_from("¤".to_string()).unwrap(),
+      Unprintable::Char('¤')
+    );
+    assert!(Unprintable::try_from("ab".to_string()).is_err());
+  }
+
+  #[test]
+  fn style_runs() {
+    let mut parser = vt100::Parser::new(2, 10, 0);
+    parser.process("a\x1b[1m界\u{200b}\x1b[0mb  \r\n\x1b[1mc  ".as_bytes());
+    let screen = parser.screen();
+    let runs = |row| super::style_runs(screen, row, &false, |c| c.bold());
+    assert_eq!(
+      runs(0),
+      [
+        (false, "a".to_string()),
+        (true, " ".to_string()),
+        (false, "b".to_string())
+      ]
+    );
+    // Blank cells with another style are kept.
+    assert_eq!(runs(1), [(true, "c  ".to_string()), (false, String::new())]);
+  }
+
+  #[test]
+  fn line_ending() {
+    let mut parser = vt100::Parser::new(3, 20, 0);
+    parser.process(b"one\r\ntwo\r\n");
+    let contents = parser.screen().contents();
+    assert_eq!(LineEnding::Lf.apply(contents.clone()), "one\ntwo");
+    assert_eq!(LineEnding::CrLf.apply(contents), "one\r\ntwo");
+  }
 }
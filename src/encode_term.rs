@@ -10,30 +10,20 @@ pub const SS3: &str = "\x1bO";
 
 /// Specifies terminal modes/configuration that can influence how a KeyCode
 /// is encoded when being sent to and application via the pty.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct KeyCodeEncodeModes {
   pub enable_csi_u_key_encoding: bool,
   pub application_cursor_keys: bool,
   pub newline_mode: bool,
 }
 
-impl Default for KeyCodeEncodeModes {
-  fn default() -> Self {
-    KeyCodeEncodeModes {
-      enable_csi_u_key_encoding: false,
-      application_cursor_keys: false,
-      newline_mode: false,
-    }
-  }
-}
-
 /// Returns the xterm compatible byte sequence that represents this KeyCode
 /// and Modifier combination.
 pub fn encode_key(key: &Key, modes: KeyCodeEncodeModes) -> Result<String> {
   use KeyCode::*;
 
-  let code = key.code().clone();
-  let mods = key.mods().clone();
+  let code = *key.code();
+  let mods = *key.mods();
 
   let mut buf = String::new();
 
@@ -45,7 +35,7 @@ pub fn encode_key(key: &Key, modes: KeyCodeEncodeModes) -> Result<String> {
       if (c.is_ascii_punctuation() || c.is_ascii_uppercase())
         && mods.contains(KeyModifiers::SHIFT) =>
     {
-      mods.clone().difference(KeyModifiers::SHIFT)
+      mods.difference(KeyModifiers::SHIFT)
     }
     _ => mods,
   };
@@ -229,6 +219,18 @@ pub fn encode_key(key: &Key, modes: KeyCodeEncodeModes) -> Result<String> {
           10 => "\x1b[21",
           11 => "\x1b[23",
           12 => "\x1b[24",
+          13 => "\x1b[25",
+          14 => "\x1b[26",
+          15 => "\x1b[28",
+          16 => "\x1b[29",
+          17 => "\x1b[31",
+          18 => "\x1b[32",
+          19 => "\x1b[33",
+          20 => "\x1b[34",
+          21 => "\x1b[42",
+          22 => "\x1b[43",
+          23 => "\x1b[44",
+          24 => "\x1b[45",
           _ => panic!("unhandled fkey number {}", n),
         };
         let encoded_mods = encode_modifiers(mods);
@@ -266,10 +268,7 @@ fn encode_modifiers(mods: KeyModifiers) -> u8 {
 /// or could be a key that a user legitimately wants to process in their
 /// terminal application
 fn is_ambiguous_ascii_ctrl(c: char) -> bool {
-  match c {
-    'i' | 'I' | 'm' | 'M' | '[' | '{' | '@' => true,
-    _ => false,
-  }
+  matches!(c, 'i' | 'I' | 'm' | 'M' | '[' | '{' | '@')
 }
 
 /// Map c to its Ctrl equivalent.
@@ -361,3 +360,58 @@ pub fn normalize_shift_to_upper_case(
     code
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(key: &str) -> String {
+    encode_key(&Key::parse(key).unwrap(), KeyCodeEncodeModes::default())
+      .unwrap()
+  }
+
+  #[test]
+  fn alt_printable() {
+    let table = [
+      ("<M-x>", "\x1bx"),
+      ("<A-x>", "\x1bx"),
+      ("<M-X>", "\x1bX"),
+      ("<M-.>", "\x1b."),
+      ("<M-/>", "\x1b/"),
+      ("<M-1>", "\x1b1"),
+      ("<M-Space>", "\x1b "),
+      ("<M-é>", "\x1bé"),
+      ("<C-M-a>", "\x1b\x01"),
+    ];
+    for (key, expected) in table {
+      assert_eq!(encode(key), expected, "{}", key);
+    }
+  }
+
+  #[test]
+  fn function_keys() {
+    let table = [
+      ("<F1>", "\x1bOP"),
+      ("<F4>", "\x1bOS"),
+      ("<F5>", "\x1b[15~"),
+      ("<F12>", "\x1b[24~"),
+      ("<F13>", "\x1b[25~"),
+      ("<F14>", "\x1b[26~"),
+      ("<F15>", "\x1b[28~"),
+      ("<F16>", "\x1b[29~"),
+      ("<F17>", "\x1b[31~"),
+      ("<F18>", "\x1b[32~"),
+      ("<F19>", "\x1b[33~"),
+      ("<F20>", "\x1b[34~"),
+      ("<F21>", "\x1b[42~"),
+      ("<F22>", "\x1b[43~"),
+      ("<F23>", "\x1b[44~"),
+      ("<F24>", "\x1b[45~"),
+      ("<S-F13>", "\x1b[25;2~"),
+      ("<C-F24>", "\x1b[45;5~"),
+    ];
+    for (key, expected) in table {
+      assert_eq!(encode(key), expected, "{}", key);
+    }
+  }
+}
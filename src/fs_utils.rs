@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Writes a file atomically: `write` creates a temporary file in the same
+/// directory, which is then renamed to `path`. Whoever watches `path` sees
+/// either the old file or the complete new one.
+pub fn write_atomic<F>(path: &str, write: F) -> Result<()>
+where
+  F: FnOnce(&Path) -> Result<()>,
+{
+  let path = Path::new(path);
+  let tmp = tmp_path(path)?;
+  let result = write(&tmp).and_then(|()| {
+    std::fs::rename(&tmp, path)
+      .with_context(|| format!("Failed to write {}", path.display()))
+  });
+  if result.is_err() {
+    let _r = std::fs::remove_file(&tmp);
+  }
+  result
+}
+
+/// `dir/.name.<pid>.tmp.ext`. The extension is kept, because image formats
+/// are picked by it.
+fn tmp_path(path: &Path) -> Result<PathBuf> {
+  let stem = path
+    .file_stem()
+    .with_context(|| format!("Not a file path: {}", path.display()))?;
+  let mut name =
+    format!(".{}.{}.tmp", stem.to_string_lossy(), std::process::id());
+  if let Some(ext) = path.extension() {
+    name.push('.');
+    name.push_str(&ext.to_string_lossy());
+  }
+  Ok(path.with_file_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+  use anyhow::bail;
+
+  use super::*;
+
+  #[test]
+  fn write_atomic_renames() {
+    let dir = std::env::temp_dir()
+      .join(format!("virterm-fs-utils-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("screen.txt");
+    let path = path.to_str().unwrap();
+
+    write_atomic(path, |tmp| {
+      assert_eq!(tmp.extension().unwrap(), "txt");
+      std::fs::write(tmp, "hello")?;
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "hello");
+
+    // A failed write keeps the old file and removes the temporary one.
+    let result = write_atomic(path, |tmp| {
+      std::fs::write(tmp, "partial")?;
+      bail!("failed")
+    });
+    assert!(result.is_err());
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "hello");
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 
 use anyhow::bail;
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
@@ -20,6 +20,7 @@ static KEYS: phf::Map<&'static str, KeyCode> = phf::phf_map! {
   "insert" => KeyCode::Insert,
   "nul" => KeyCode::Null,
   "esc" => KeyCode::Esc,
+  "space" => KeyCode::Char(' '),
 
   "lt" => KeyCode::Char('<'),
   "gt" => KeyCode::Char('>'),
@@ -37,12 +38,25 @@ static KEYS: phf::Map<&'static str, KeyCode> = phf::phf_map! {
   "f10" => KeyCode::F(10),
   "f11" => KeyCode::F(11),
   "f12" => KeyCode::F(12),
+  "f13" => KeyCode::F(13),
+  "f14" => KeyCode::F(14),
+  "f15" => KeyCode::F(15),
+  "f16" => KeyCode::F(16),
+  "f17" => KeyCode::F(17),
+  "f18" => KeyCode::F(18),
+  "f19" => KeyCode::F(19),
+  "f20" => KeyCode::F(20),
+  "f21" => KeyCode::F(21),
+  "f22" => KeyCode::F(22),
+  "f23" => KeyCode::F(23),
+  "f24" => KeyCode::F(24),
 };
 
 static SPECIAL_CHARS: phf::Map<char, &str> = phf::phf_map! {
   '<' => "LT",
   '>' => "GT",
   '-' => "Minus",
+  ' ' => "Space",
 };
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq)]
@@ -68,8 +82,8 @@ impl From<KeyEvent> for Key {
   }
 }
 
-impl ToString for Key {
-  fn to_string(&self) -> String {
+impl Display for Key {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     let mut buf = String::new();
 
     buf.push('<');
@@ -117,7 +131,7 @@ impl ToString for Key {
 
     buf.push('>');
 
-    buf
+    f.write_str(buf.as_str())
   }
 }
 
@@ -160,9 +174,15 @@ impl KeyParser<'_> {
     let mods = parser.take_mods()?;
     let code = {
       let word = parser.take_word()?;
-      if let Some(code) = KEYS.get(word.to_ascii_lowercase().as_str()) {
-        code.clone()
-      } else if word.len() == 1 {
+      if word.is_empty() {
+        // Punctuation and other non-alphanumeric chars: <.>, <M-.>
+        match parser.take_char() {
+          Some(ch) if ch != '>' => KeyCode::Char(ch),
+          _ => bail!("Expected key code"),
+        }
+      } else if let Some(code) = KEYS.get(word.to_ascii_lowercase().as_str()) {
+        *code
+      } else if word.chars().count() == 1 {
         KeyCode::Char(word.chars().next().unwrap())
       } else {
         bail!("Wrong key code: \"{}\"", word);
@@ -189,8 +209,7 @@ impl KeyParser<'_> {
 
   fn take_word(&mut self) -> anyhow::Result<&str> {
     let mut next_pos = self.pos;
-    let mut chars = self.text[self.pos..].chars();
-    while let Some(ch) = chars.next() {
+    for ch in self.text[self.pos..].chars() {
       if ch.is_alphanumeric() {
         next_pos += ch.len_utf8();
       } else {
@@ -202,15 +221,26 @@ impl KeyParser<'_> {
     Ok(&self.text[start..next_pos])
   }
 
+  fn take_char(&mut self) -> Option<char> {
+    let ch = self.text[self.pos..].chars().next()?;
+    self.pos += ch.len_utf8();
+    Some(ch)
+  }
+
   fn take_mods(&mut self) -> anyhow::Result<KeyModifiers> {
     let mut mods = KeyModifiers::NONE;
     let mut pos = self.pos;
-    while pos + 1 < self.text.len() && &self.text[pos + 1..pos + 2] == "-" {
-      match &self.text[pos..pos + 1] {
-        "c" | "C" => mods = mods.union(KeyModifiers::CONTROL),
-        "s" | "S" => mods = mods.union(KeyModifiers::SHIFT),
-        "m" | "M" => mods = mods.union(KeyModifiers::ALT),
-        ch => bail!("Wrong key modifier: \"{}\"", ch),
+    // Compare bytes, slicing the str could split a multibyte char.
+    let bytes = self.text.as_bytes();
+    while pos + 1 < bytes.len() && bytes[pos + 1] == b'-' {
+      match bytes[pos] {
+        b'c' | b'C' => mods = mods.union(KeyModifiers::CONTROL),
+        b's' | b'S' => mods = mods.union(KeyModifiers::SHIFT),
+        b'm' | b'M' | b'a' | b'A' => mods = mods.union(KeyModifiers::ALT),
+        _ => {
+          let ch = self.text[pos..].chars().next().unwrap_or_default();
+          bail!("Wrong key modifier: \"{}\"", ch)
+        }
       }
       pos += 2;
     }
@@ -248,7 +278,11 @@ mod tests {
       Key::parse("<f12>").unwrap(),
       Key(KeyEvent::new(KeyCode::F(12), KeyModifiers::NONE))
     );
-    assert_matches!(Key::parse("<F13>"), Err(_));
+    assert_eq!(
+      Key::parse("<F24>").unwrap(),
+      Key(KeyEvent::new(KeyCode::F(24), KeyModifiers::NONE))
+    );
+    assert_matches!(Key::parse("<F25>"), Err(_));
 
     assert_eq!(
       Key::parse("<a>").unwrap(),
@@ -258,6 +292,16 @@ mod tests {
       Key::parse("<C-a>").unwrap(),
       Key(KeyEvent::new(KeyCode::Char('a'), KeyModifiers::CONTROL))
     );
+    assert_eq!(
+      Key::parse("<m-.>").unwrap(),
+      Key(KeyEvent::new(KeyCode::Char('.'), KeyModifiers::ALT))
+    );
+    assert_eq!(
+      Key::parse("<a-x>").unwrap(),
+      Key(KeyEvent::new(KeyCode::Char('x'), KeyModifiers::ALT))
+    );
+    assert_matches!(Key::parse("<>"), Err(_));
+    assert_matches!(Key::parse("<M->"), Err(_));
     assert_eq!(
       Key::parse("<C-M-a>").unwrap(),
       Key(KeyEvent::new(
@@ -299,5 +343,8 @@ mod tests {
     in_out("<Minus>");
     in_out("<LT>");
     in_out("<GT>");
+    in_out("<Space>");
+    in_out("<M-.>");
+    in_out("<M-Space>");
   }
 }
@@ -0,0 +1,166 @@
+//! Finds stray control characters in the output: C0 controls that terminals
+//! don't use for output, DEL and C1 controls (UTF-8 encoded), outside of the
+//! escape sequences that contain them. They usually come from a program that
+//! builds escape sequences wrong.
+
+/// Number of the first stray controls that are kept.
+const MAX_STRAY: usize = 1000;
+
+/// A control character found where no terminal expects one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stray {
+  /// Offset in the output of the process, of the first byte for C1 ones.
+  pub pos: u64,
+  /// Code point, e.g. `0x00` for NUL or `0x9b` for C1 CSI.
+  pub code: u8,
+}
+
+#[derive(Default)]
+pub struct LintScanner {
+  state: State,
+  pos: u64,
+  /// The first `MAX_STRAY` stray controls.
+  stray: Vec<Stray>,
+  count: usize,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum State {
+  #[default]
+  Ground,
+  /// First byte (0xc2) of a code point from U+0080 to U+00BF.
+  Utf8C2,
+  Esc,
+  /// Intermediate bytes of a two byte escape, e.g. `ESC ( B`.
+  EscIntermediate,
+  Csi,
+  /// OSC (`bel` is `true`) or DCS, APC, PM, SOS: anything up to ST.
+  String {
+    bel: bool,
+  },
+  /// ESC inside of a string, the start of ST.
+  StringEsc,
+}
+
+impl LintScanner {
+  pub fn scan(&mut self, bytes: &[u8]) {
+    for byte in bytes {
+      self.feed(*byte);
+      self.pos += 1;
+    }
+  }
+
+  pub fn stray(&self) -> &[Stray] {
+    &self.stray
+  }
+
+  /// Number of all the stray controls found, also the ones not kept.
+  pub fn count(&self) -> usize {
+    self.count
+  }
+
+  fn feed(&mut self, byte: u8) {
+    self.state = match (self.state, byte) {
+      (State::Ground, 0x1b) => State::Esc,
+      (State::Ground, 0xc2) => State::Utf8C2,
+      (State::Ground, byte) => {
+        self.check(byte);
+        State::Ground
+      }
+      (State::Utf8C2, 0x80..=0x9f) => {
+        self.push(self.pos - 1, byte);
+        State::Ground
+      }
+      (State::Utf8C2, 0xa0..=0xbf) => State::Ground,
+      // Invalid UTF-8, the byte doesn't continue the code point.
+      (State::Utf8C2, byte) => {
+        self.state = State::Ground;
+        return self.feed(byte);
+      }
+      (State::Esc, b'[') => State::Csi,
+      (State::Esc, b']') => State::String { bel: true },
+      (State::Esc, b'P' | b'X' | b'^' | b'_') => State::String { bel: false },
+      (State::Esc, 0x20..=0x2f) => State::EscIntermediate,
+      (State::EscIntermediate, 0x20..=0x2f) => State::EscIntermediate,
+      (State::Esc | State::EscIntermediate, 0x30..=0x7e) => State::Ground,
+      (State::Csi, 0x20..=0x3f) => State::Csi,
+      (State::Csi, 0x40..=0x7e) => State::Ground,
+      // An ESC starts a new sequence, CAN and SUB cancel the sequence and
+      // are stray themselves. Other controls are executed in the middle of
+      // the sequence.
+      (State::Esc | State::EscIntermediate | State::Csi, 0x1b) => State::Esc,
+      (state @ (State::Esc | State::EscIntermediate | State::Csi), byte) => {
+        self.check(byte);
+        match byte {
+          0x00..=0x1f | 0x7f if byte != 0x18 && byte != 0x1a => state,
+          _ => State::Ground,
+        }
+      }
+      (State::String { bel: true }, 0x07) => State::Ground,
+      (State::String { .. }, 0x1b) => State::StringEsc,
+      (State::String { .. }, 0x18 | 0x1a) => {
+        self.check(byte);
+        State::Ground
+      }
+      (state @ State::String { .. }, _) => state,
+      (State::StringEsc, b'\\') => State::Ground,
+      (State::StringEsc, byte) => {
+        self.state = State::Esc;
+        return self.feed(byte);
+      }
+    };
+  }
+
+  /// Records `byte` if it's a control that terminals don't expect in output.
+  /// BEL, BS, HT, LF, VT, FF and CR are expected.
+  fn check(&mut self, byte: u8) {
+    if matches!(byte, 0x00..=0x06 | 0x0e..=0x1f | 0x7f) {
+      self.push(self.pos, byte);
+    }
+  }
+
+  fn push(&mut self, pos: u64, code: u8) {
+    self.count += 1;
+    if self.stray.len() < MAX_STRAY {
+      self.stray.push(Stray { pos, code });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn stray(chunks: &[&[u8]]) -> Vec<(u64, u8)> {
+    let mut scanner = LintScanner::default();
+    for chunk in chunks {
+      scanner.scan(chunk);
+    }
+    scanner.stray().iter().map(|s| (s.pos, s.code)).collect()
+  }
+
+  #[test]
+  fn clean_output() {
+    let output = "\x1b[1;31mred\x1b[0m\r\n\ttab\x08\x07 \x1b]0;title\x07\
+      \x1b]8;;http://a\x1b\\link\x1b]8;;\x1b\\ \x1b(0q\x1b(B \u{a0}é😀";
+    assert_eq!(stray(&[output.as_bytes()]), vec![]);
+  }
+
+  #[test]
+  fn stray_controls() {
+    // NUL, DEL, SO and a C1 CSI in UTF-8.
+    assert_eq!(
+      stray(&[b"a\x00b\x7f\x0e", "\u{9b}".as_bytes()]),
+      vec![(1, 0x00), (3, 0x7f), (4, 0x0e), (5, 0x9b)]
+    );
+    // A C1 control split between reads, and a C0 one inside of CSI.
+    assert_eq!(
+      stray(&[b"\xc2", b"\x85\x1b[1\x01m"]),
+      vec![(0, 0x85), (5, 0x01)]
+    );
+    // Controls inside of strings aren't stray, CAN cancels a sequence.
+    assert_eq!(stray(&[b"\x1b]0;\x01\x07\x1b[1\x18m"]), vec![(9, 0x18)]);
+    // Invalid UTF-8 after 0xc2.
+    assert_eq!(stray(&[b"\xc2\x01"]), vec![(1, 0x01)]);
+  }
+}
@@ -0,0 +1,123 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use log::{Level, Log, Metadata, Record};
+
+/// Number of recent log records kept for `vt.logs()`.
+const MAX_RECORDS: usize = 1000;
+
+static RECENT: Mutex<LogBuffer> = Mutex::new(LogBuffer::new());
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogRecord {
+  pub level: Level,
+  pub target: String,
+  pub message: String,
+}
+
+struct LogBuffer {
+  records: VecDeque<LogRecord>,
+}
+
+impl LogBuffer {
+  const fn new() -> Self {
+    LogBuffer {
+      records: VecDeque::new(),
+    }
+  }
+
+  fn push(&mut self, record: LogRecord) {
+    if self.records.len() == MAX_RECORDS {
+      self.records.pop_front();
+    }
+    self.records.push_back(record);
+  }
+
+  /// Records with `level` or a more severe one, oldest first.
+  fn get(&self, level: Level) -> Vec<LogRecord> {
+    self
+      .records
+      .iter()
+      .filter(|r| r.level <= level)
+      .cloned()
+      .collect()
+  }
+}
+
+/// Logs with `inner` and keeps the logged records in memory.
+struct TeeLogger {
+  inner: env_logger::Logger,
+}
+
+impl Log for TeeLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    self.inner.enabled(metadata)
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.inner.matches(record) {
+      return;
+    }
+    self.inner.log(record);
+    lock().push(LogRecord {
+      level: record.level(),
+      target: record.target().to_string(),
+      message: record.args().to_string(),
+    });
+  }
+
+  fn flush(&self) {
+    self.inner.flush();
+  }
+}
+
+fn lock() -> std::sync::MutexGuard<'static, LogBuffer> {
+  RECENT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn init(inner: env_logger::Logger) {
+  log::set_max_level(inner.filter());
+  log::set_boxed_logger(Box::new(TeeLogger { inner }))
+    .expect("Logger is already set");
+}
+
+/// Recent records with `level` or a more severe one, oldest first.
+pub fn recent(level: Level) -> Vec<LogRecord> {
+  lock().get(level)
+}
+
+pub fn clear() {
+  lock().records.clear();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn record(level: Level, message: &str) -> LogRecord {
+    LogRecord {
+      level,
+      target: "virterm".to_string(),
+      message: message.to_string(),
+    }
+  }
+
+  #[test]
+  fn buffer() {
+    let mut buf = LogBuffer::new();
+    buf.push(record(Level::Info, "info"));
+    buf.push(record(Level::Warn, "warn"));
+    buf.push(record(Level::Error, "error"));
+    assert_eq!(
+      buf.get(Level::Warn),
+      vec![record(Level::Warn, "warn"), record(Level::Error, "error")]
+    );
+    assert_eq!(buf.get(Level::Trace).len(), 3);
+
+    for i in 0..MAX_RECORDS {
+      buf.push(record(Level::Info, &i.to_string()));
+    }
+    let records = buf.get(Level::Trace);
+    assert_eq!(records.len(), MAX_RECORDS);
+    assert_eq!(records[0].message, "0");
+  }
+}
@@ -1,79 +1,619 @@
-#![feature(async_closure)]
-
+mod cast;
+mod clock;
+mod dump_ansi;
+mod dump_html;
+mod dump_json;
 mod dump_png;
 mod dump_txt;
 mod encode_term;
+mod fs_utils;
 mod key;
+mod lint;
+mod logs;
 mod lua_utils;
+mod margins;
 mod mouse;
+mod osc;
 mod proc;
+mod profile;
+mod trace;
+mod vt;
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::{arg, command};
+use dump_png::{ComposeOpts, Renderer};
 use lua_utils::to_lua_err;
-use mlua::{Lua, LuaSerdeExt};
-use proc::{LuaProc, Proc, ProcConfig};
+use mlua::{Lua, LuaOptions, LuaSerdeExt, StdLib};
+use proc::{LuaProc, Proc, ProcConfig, ScreenConfig, ShellConfig};
 use tokio::io::AsyncReadExt;
+use vt::Vt;
+
+/// Number of the differing cells described by `vt.assert_screens_equal()`.
+const SCREEN_DIFF_LIMIT: usize = 10;
+
+/// Capabilities exposed to scripts as `vt.capabilities` so shared test
+/// libraries can check for a feature before using it.
+const CAPABILITIES: &[&str] = &[
+  "color_replies",
+  "compose_png",
+  "cursor_style",
+  "dump_diff_png",
+  "dump_png",
+  "dump_txt",
+  "export_ansi",
+  "export_html",
+  "is_synchronizing",
+  "lint_output",
+  "mouse",
+  "normalize_cast",
+  "render_png",
+  "replacement_char_count",
+  "replay_cast",
+  "screen_hash",
+  "scroll_region",
+  "wait_row",
+  "wait_text_regex",
+  #[cfg(not(windows))]
+  "signals",
+];
 
 #[tokio::main]
-async fn main() -> () {
-  env_logger::builder()
-    .format_timestamp(None)
-    .filter_level(log::LevelFilter::Info)
-    .init();
+async fn main() {
+  logs::init(
+    env_logger::builder()
+      .format_timestamp(None)
+      .filter_level(log::LevelFilter::Info)
+      .build(),
+  );
 
   match run_cli().await {
     Ok(()) => (),
     Err(err) => {
       log::error!("{}", err.to_string());
-      std::process::exit(1);
+      let code = match err.downcast_ref::<ScriptTimeout>() {
+        Some(_) => TIMEOUT_EXIT_CODE,
+        None => 1,
+      };
+      std::process::exit(code);
     }
   };
 
   std::process::exit(0);
 }
 
+/// Kills the started processes, writes the trace of `--trace-out` and exits
+/// when virterm is interrupted. The handler runs in its own task, so it works
+/// while the script is waiting.
+fn handle_signals(trace_out: Option<String>) {
+  tokio::spawn(async move {
+    match interrupted().await {
+      Ok(sig) => {
+        log::error!("Interrupted");
+        proc::kill_all();
+        if let Some(path) = &trace_out {
+          write_trace(path);
+        }
+        std::process::exit(128 + sig);
+      }
+      Err(err) => log::warn!("Failed to listen for signals: {}", err),
+    }
+  });
+}
+
+#[cfg(unix)]
+async fn interrupted() -> std::io::Result<libc::c_int> {
+  use tokio::signal::unix::{signal, SignalKind};
+
+  let mut sigint = signal(SignalKind::interrupt())?;
+  let mut sigterm = signal(SignalKind::terminate())?;
+  let sig = tokio::select! {
+    _ = sigint.recv() => libc::SIGINT,
+    _ = sigterm.recv() => libc::SIGTERM,
+  };
+  Ok(sig)
+}
+
+#[cfg(windows)]
+async fn interrupted() -> std::io::Result<libc::c_int> {
+  tokio::signal::ctrl_c().await?;
+  Ok(libc::SIGINT)
+}
+
+/// Functions of `vt` that start processes or touch files, removed with
+/// `--sandbox`.
+const SANDBOX_VT_REMOVED: &[&str] = &[
+  "start",
+  "shell",
+  "replay_cast",
+  "normalize_cast",
+  "compose_png",
+  "render_png",
+];
+
+/// Functions of `os` that run commands, change files or read the
+/// environment, removed with `--sandbox`.
+const SANDBOX_OS_REMOVED: &[&str] = &[
+  "execute",
+  "exit",
+  "getenv",
+  "remove",
+  "rename",
+  "setlocale",
+  "tmpname",
+];
+
+/// Capabilities that read or write files, not available with `--sandbox`.
+const SANDBOX_CAPABILITIES_REMOVED: &[&str] = &[
+  "compose_png",
+  "dump_diff_png",
+  "dump_png",
+  "dump_txt",
+  "export_ansi",
+  "export_html",
+  "normalize_cast",
+  "render_png",
+  "replay_cast",
+];
+
+/// Exit code when the script runs longer than `--timeout`. Same as the one of
+/// the `timeout` command.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+#[derive(Debug)]
+struct ScriptTimeout(Duration);
+
+impl std::fmt::Display for ScriptTimeout {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Script timed out after {}ms", self.0.as_millis())
+  }
+}
+
+impl std::error::Error for ScriptTimeout {}
+
 async fn run_cli() -> anyhow::Result<()> {
   let matches = command!()
-    .arg(arg!(<script> "Command to run"))
+    .arg(arg!(<script> ... "Scripts to run one after another"))
+    .arg(
+      arg!(--timeout <MS> "Abort each script after this many milliseconds")
+        .required(false)
+        .validator(|s| s.parse::<u64>()),
+    )
+    .arg(
+      arg!(--sandbox "Run the scripts without access to processes and files"),
+    )
+    .arg(
+      arg!(--"trace-out" <PATH> "Write the timing of api calls as a Chrome trace")
+        .required(false),
+    )
     .get_matches();
 
-  let script = matches.value_of("script").unwrap();
+  let scripts: Vec<&str> = matches.values_of("script").unwrap().collect();
+  let timeout = matches
+    .value_of("timeout")
+    .map(|ms| Duration::from_millis(ms.parse().unwrap()));
+  let sandbox = matches.is_present("sandbox");
+  proc::set_sandbox(sandbox);
 
-  run_lua(script).await?;
+  let trace_out = matches.value_of("trace-out");
+  if trace_out.is_some() {
+    profile::enable();
+  }
+  handle_signals(trace_out.map(String::from));
 
+  let result = run_scripts(&scripts, timeout, sandbox).await;
+  if let Some(path) = trace_out {
+    write_trace(path);
+  }
+  result
+}
+
+fn write_trace(path: &str) {
+  if let Err(err) = profile::write(path) {
+    log::error!("Failed to write the trace to {}: {}", path, err);
+  }
+}
+
+async fn run_scripts(
+  scripts: &[&str],
+  timeout: Option<Duration>,
+  sandbox: bool,
+) -> Result<()> {
+  if let [script] = scripts {
+    return run_script(script, timeout, sandbox).await;
+  }
+
+  let mut results = Vec::with_capacity(scripts.len());
+  let mut timed_out = None;
+  for script in scripts {
+    log::info!("Running {}", script);
+    let started = Instant::now();
+    let result = run_script(script, timeout, sandbox).await;
+    if let Err(err) = &result {
+      log::error!("{}: {}", script, err);
+      if let Some(ScriptTimeout(timeout)) = err.downcast_ref::<ScriptTimeout>()
+      {
+        timed_out = Some(*timeout);
+      }
+    }
+    // Processes of one script must not be seen by the next one.
+    proc::kill_all();
+    results.push((script, result.is_ok(), clock::elapsed(started)));
+  }
+
+  let failed = results.iter().filter(|(_, ok, _)| !ok).count();
+  for (script, ok, time) in &results {
+    let status = if *ok { "PASS" } else { "FAIL" };
+    log::info!("{} {} ({}ms)", status, script, time.as_millis());
+  }
+  log::info!("{} passed, {} failed", results.len() - failed, failed);
+  if failed > 0 {
+    let msg = format!("{} of {} scripts failed", failed, results.len());
+    // Keep the exit code of a timeout when several scripts run.
+    if let Some(timeout) = timed_out {
+      return Err(anyhow::Error::new(ScriptTimeout(timeout)).context(msg));
+    }
+    bail!(msg);
+  }
   Ok(())
 }
 
-async fn run_lua(script: &str) -> Result<()> {
-  let lua = Lua::new();
+/// Lua state without the `io` and `package` libraries, `dofile()`,
+/// `loadfile()` and the functions of `SANDBOX_OS_REMOVED`. `load()` only
+/// takes text chunks, bytecode can break out of the sandbox.
+fn sandboxed_lua() -> Result<Lua> {
+  let libs = StdLib::ALL_SAFE ^ StdLib::IO ^ StdLib::PACKAGE;
+  let lua = Lua::new_with(libs, LuaOptions::default())?;
+  {
+    let globals = lua.globals();
+    globals.set("dofile", mlua::Value::Nil)?;
+    globals.set("loadfile", mlua::Value::Nil)?;
+    let os: mlua::Table = globals.get("os")?;
+    for name in SANDBOX_OS_REMOVED {
+      os.set(*name, mlua::Value::Nil)?;
+    }
+  }
+  lua
+    .load(
+      "local load, select = load, select
+      function _G.load(chunk, name, _, ...)
+        -- An explicit nil env would set _ENV of the chunk to nil.
+        if select('#', ...) > 0 then
+          return load(chunk, name, 't', (...))
+        end
+        return load(chunk, name, 't')
+      end",
+    )
+    .exec()?;
+  Ok(lua)
+}
+
+/// Runs the script in a fresh Lua state, aborting it after `timeout`.
+async fn run_script(
+  script: &str,
+  timeout: Option<Duration>,
+  sandbox: bool,
+) -> Result<()> {
+  profile::start_script(script);
+  match timeout {
+    Some(timeout) => {
+      tokio::select! {
+        result = run_lua(script, sandbox) => result,
+        _ = tokio::time::sleep(timeout) => {
+          proc::kill_all();
+          Err(ScriptTimeout(timeout).into())
+        }
+      }
+    }
+    None => run_lua(script, sandbox).await,
+  }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct LogsOpts {
+  level: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct ReplayCastOpts {
+  at_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct NormalizeCastOpts {
+  /// Where to write the normalized cast instead of overwriting the source.
+  out: Option<String>,
+  #[serde(default)]
+  quantize_ms: u64,
+}
+
+async fn run_lua(script: &str, sandbox: bool) -> Result<()> {
+  let lua = if sandbox {
+    sandboxed_lua()?
+  } else {
+    Lua::new()
+  };
+  // Reset the settings left by a previous script.
+  proc::set_input_delay(0);
+  logs::clear();
+
+  lua.globals().set("vt", vt_table(&lua, sandbox)?)?;
+
+  proc::set_script_dir(
+    std::path::Path::new(script)
+      .parent()
+      .map(|dir| dir.to_path_buf()),
+  );
+
+  let mut script = tokio::fs::File::open(script).await?;
+  let mut src = String::new();
+  script.read_to_string(&mut src).await?;
+  if let Err(err) = lua.load(src.as_str()).exec_async().await {
+    // Don't leave the started processes running after a failed script.
+    proc::kill_all();
+    return Err(err.into());
+  }
+
+  Ok(())
+}
 
+/// The `vt` table of the Lua api.
+fn vt_table(lua: &Lua, sandbox: bool) -> Result<mlua::Table<'_>> {
   let vt = lua.create_table()?;
 
-  let start =
-    lua.create_function(|lua, (cmd, cfg_val): (String, mlua::Value)| {
+  vt.set("version", env!("CARGO_PKG_VERSION"))?;
+  let capabilities = lua.create_table()?;
+  for name in CAPABILITIES {
+    if !(sandbox && SANDBOX_CAPABILITIES_REMOVED.contains(name)) {
+      capabilities.set(*name, true)?;
+    }
+  }
+  vt.set("capabilities", capabilities)?;
+
+  let start = profile::async_function(
+    lua,
+    "vt.start",
+    async move |lua, (cmd, cfg_val): (String, mlua::Value)| {
       let cfg: Option<ProcConfig> = lua.from_value(cfg_val)?;
       let cfg = cfg.unwrap_or_default();
       let proc = Proc::shell(cmd.as_str(), &cfg).map_err(to_lua_err)?;
+      if cfg.settle_ms > 0 {
+        let vt = proc.vt.clone();
+        if proc::settle(&vt, cfg.settle_ms).await.is_err() {
+          log::warn!(
+            "{}: The screen didn't settle for {}ms",
+            proc.log_name(),
+            cfg.settle_ms
+          );
+        }
+      }
       let proc = LuaProc::new(proc);
       Ok(proc)
-    })?;
+    },
+  )?;
   vt.set("start", start)?;
 
-  let sleep = lua.create_async_function(async move |_, millis: u64| {
-    tokio::time::sleep(Duration::from_millis(millis)).await;
-    Ok(())
+  let shell = profile::function(lua, "vt.shell", |lua, cfg: mlua::Value| {
+    let cfg: Option<ShellConfig> = lua.from_value(cfg)?;
+    let cfg = cfg.unwrap_or_default();
+    log::info!("shell(): {:?} login={}", cfg.kind, cfg.login);
+    let proc = Proc::interactive_shell(cfg).map_err(to_lua_err)?;
+    Ok(LuaProc::new(proc))
   })?;
+  vt.set("shell", shell)?;
+
+  let sleep =
+    profile::async_function(lua, "vt.sleep", async move |_, millis: u64| {
+      tokio::time::sleep(Duration::from_millis(millis)).await;
+      Ok(())
+    })?;
   vt.set("sleep", sleep)?;
 
-  lua.globals().set("vt", vt)?;
+  let set_input_delay =
+    profile::function(lua, "vt.set_input_delay", |_, millis: u64| {
+      log::info!("set_input_delay(): {}", millis);
+      proc::set_input_delay(millis);
+      Ok(())
+    })?;
+  vt.set("set_input_delay", set_input_delay)?;
 
-  let mut script = tokio::fs::File::open(script).await?;
-  let mut src = String::new();
-  script.read_to_string(&mut src).await?;
-  lua.load(src.as_str()).exec_async().await?;
+  let new_screen =
+    profile::function(lua, "vt.new_screen", |lua, cfg: mlua::Value| {
+      let cfg: Option<ScreenConfig> = lua.from_value(cfg)?;
+      let cfg = cfg.unwrap_or_default();
+      log::info!("new_screen(): {:?}", cfg);
+      let proc = Proc::screen(&cfg).map_err(to_lua_err)?;
+      Ok(LuaProc::new(proc))
+    })?;
+  vt.set("new_screen", new_screen)?;
 
-  Ok(())
+  let parse_into = profile::function(
+    lua,
+    "vt.parse_into",
+    |_, (proc, bytes): (LuaProc, mlua::String)| {
+      let mut proc = proc.lock()?;
+      log::info!(
+        "parse_into(): {} {} bytes",
+        proc.log_name(),
+        bytes.as_bytes().len()
+      );
+      proc.parse_output(bytes.as_bytes());
+      Ok(())
+    },
+  )?;
+  vt.set("parse_into", parse_into)?;
+
+  let replay_cast = profile::function(
+    lua,
+    "vt.replay_cast",
+    |lua, (path, opts): (String, mlua::Value)| {
+      log::info!("replay_cast(): {}", path);
+      let opts: Option<ReplayCastOpts> = lua.from_value(opts)?;
+      let opts = opts.unwrap_or_default();
+      let cast = cast::read(path.as_str()).map_err(to_lua_err)?;
+      let cfg = ScreenConfig {
+        width: cast.width,
+        height: cast.height,
+        ..Default::default()
+      };
+      let mut proc = Proc::screen(&cfg).map_err(to_lua_err)?;
+      cast::replay(&mut proc, &cast, opts.at_ms).map_err(to_lua_err)?;
+      Ok(LuaProc::new(proc))
+    },
+  )?;
+  vt.set("replay_cast", replay_cast)?;
+
+  let normalize_cast = profile::function(
+    lua,
+    "vt.normalize_cast",
+    |lua, (path, opts): (String, mlua::Value)| {
+      let opts: Option<NormalizeCastOpts> = lua.from_value(opts)?;
+      let opts = opts.unwrap_or_default();
+      log::info!("normalize_cast(): {} {:?}", path, opts);
+      let cast = cast::read(path.as_str()).map_err(to_lua_err)?;
+      let cast = cast::normalize(&cast, opts.quantize_ms);
+      let out = opts.out.as_deref().unwrap_or(path.as_str());
+      cast::write(out, &cast).map_err(to_lua_err)?;
+      Ok(())
+    },
+  )?;
+  vt.set("normalize_cast", normalize_cast)?;
+
+  let logs = profile::function(lua, "vt.logs", |lua, opts: mlua::Value| {
+    let opts: Option<LogsOpts> = lua.from_value(opts)?;
+    let level = match opts.and_then(|opts| opts.level) {
+      Some(level) => level.parse().map_err(to_lua_err)?,
+      None => log::Level::Trace,
+    };
+    let records = logs::recent(level);
+    let list = lua.create_table()?;
+    for (i, record) in records.into_iter().enumerate() {
+      let item = lua.create_table()?;
+      item.set("level", record.level.as_str().to_lowercase())?;
+      item.set("target", record.target)?;
+      item.set("message", record.message)?;
+      list.set(i + 1, item)?;
+    }
+    Ok(list)
+  })?;
+  vt.set("logs", logs)?;
+
+  let compose_png = profile::async_function(
+    lua,
+    "vt.compose_png",
+    async move |lua,
+                (procs, path, opts): (Vec<LuaProc>, String, mlua::Value)| {
+      log::info!("compose_png(): {} procs", procs.len());
+      let opts: Option<ComposeOpts> = lua.from_value(opts)?;
+      let opts = opts.unwrap_or_default();
+
+      let mut renderer = Renderer::new().map_err(to_lua_err)?;
+      let mut panels = Vec::with_capacity(procs.len());
+      for (i, proc) in procs.iter().enumerate() {
+        let label = opts.labels.get(i).map(|s| s.as_str());
+        let proc = proc.lock()?;
+        let vt = proc.lock_vt()?;
+        renderer.set_palette(vt.palette());
+        panels.push(renderer.render_panel(vt.screen(), label));
+      }
+      let canvas = dump_png::compose(&panels, &opts);
+      dump_png::save(&canvas, path.as_str()).map_err(to_lua_err)?;
+      Ok(())
+    },
+  )?;
+  vt.set("compose_png", compose_png)?;
+
+  let render_png = profile::async_function(
+    lua,
+    "vt.render_png",
+    async move |_, (blob, path): (mlua::String, String)| {
+      log::info!("render_png()");
+      let screen = Vt::deserialize(blob.as_bytes()).map_err(to_lua_err)?;
+      dump_png::dump_png(screen.screen(), screen.palette(), path.as_str())
+        .map_err(to_lua_err)?;
+      Ok(())
+    },
+  )?;
+  vt.set("render_png", render_png)?;
+
+  let assert_screens_equal = profile::function(
+    lua,
+    "vt.assert_screens_equal",
+    |_, (a, b): (mlua::String, mlua::String)| {
+      let a = Vt::deserialize(a.as_bytes()).map_err(to_lua_err)?;
+      let b = Vt::deserialize(b.as_bytes()).map_err(to_lua_err)?;
+      match vt::screen_diff(a.screen(), b.screen(), SCREEN_DIFF_LIMIT) {
+        Some(diff) => Err(mlua::Error::external(format!(
+          "assert_screens_equal(): The screens differ, {}",
+          diff
+        ))),
+        None => Ok(()),
+      }
+    },
+  )?;
+  vt.set("assert_screens_equal", assert_screens_equal)?;
+
+  if sandbox {
+    for name in SANDBOX_VT_REMOVED {
+      vt.set(*name, mlua::Value::Nil)?;
+    }
+  }
+  Ok(vt)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sandboxed_load() {
+    let lua = sandboxed_lua().unwrap();
+    let eval = |src: &str| lua.load(src).eval::<String>();
+    assert_eq!(eval("return load('return tostring(1)')()").unwrap(), "1");
+    assert_eq!(
+      eval("return load('return x', 'chunk', 't', { x = 'env' })()").unwrap(),
+      "env"
+    );
+    // Bytecode is refused, load() returns nil and an error.
+    let bytecode = "return tostring(load(string.dump(function() end)))";
+    assert_eq!(eval(bytecode).unwrap(), "nil");
+  }
+
+  /// Capabilities of features that aren't a function or a method of their own.
+  const NOT_API_CAPABILITIES: &[&str] =
+    &["color_replies", "mouse", "signals", "wait_text_regex"];
+
+  #[test]
+  fn capabilities() {
+    let lua = Lua::new();
+    lua
+      .globals()
+      .set("vt", vt_table(&lua, false).unwrap())
+      .unwrap();
+    let screen: mlua::Value =
+      lua.load("return vt.new_screen()").eval().unwrap();
+    lua.globals().set("screen", screen).unwrap();
+    let is_api = |name: &str| {
+      lua
+        .load(&format!(
+          "local ok, method = pcall(function() return screen.{0} end)
+          return vt.{0} ~= nil or (ok and method ~= nil)",
+          name
+        ))
+        .eval::<bool>()
+        .unwrap()
+    };
+    for name in CAPABILITIES {
+      assert_eq!(
+        is_api(name),
+        !NOT_API_CAPABILITIES.contains(name),
+        "capability {}",
+        name
+      );
+    }
+    for name in SANDBOX_CAPABILITIES_REMOVED {
+      assert!(CAPABILITIES.contains(name), "sandbox capability {}", name);
+    }
+  }
 }
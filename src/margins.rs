@@ -0,0 +1,420 @@
+//! Tracks the scrolling region (DECSTBM) and origin mode (DECOM) set by the
+//! process. vt100 keeps them private, so the output is scanned for the
+//! sequences that change them, following what vt100 does with them. The
+//! synchronized output mode (2026) and the cursor style (DECSCUSR), which
+//! vt100 ignores, are tracked too.
+
+/// Scrolling region and origin mode of one screen (main or alternate).
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Grid {
+  /// First row of the scrolling region, from 0.
+  top: u16,
+  /// Last row of the scrolling region, inclusive.
+  bottom: u16,
+  origin_mode: bool,
+  /// Origin mode saved with the cursor by DECSC.
+  saved_origin_mode: bool,
+}
+
+impl Grid {
+  fn new(rows: u16) -> Self {
+    Grid {
+      top: 0,
+      bottom: rows.saturating_sub(1),
+      origin_mode: false,
+      saved_origin_mode: false,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CursorShape {
+  Block,
+  Underline,
+  Bar,
+}
+
+impl CursorShape {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      CursorShape::Block => "block",
+      CursorShape::Underline => "underline",
+      CursorShape::Bar => "bar",
+    }
+  }
+}
+
+/// Cursor style set with `ESC [ n SP q`. Terminals start with a blinking
+/// block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CursorStyle {
+  pub shape: CursorShape,
+  pub blink: bool,
+}
+
+impl Default for CursorStyle {
+  fn default() -> Self {
+    CursorStyle {
+      shape: CursorShape::Block,
+      blink: true,
+    }
+  }
+}
+
+pub struct Margins {
+  rows: u16,
+  main: Grid,
+  alternate: Grid,
+  alternate_screen: bool,
+  /// Between `ESC [ ? 2026 h` and `ESC [ ? 2026 l`, the process is drawing a
+  /// frame.
+  synchronized: bool,
+  cursor_style: CursorStyle,
+  state: State,
+  /// Parameters and intermediates of the current CSI sequence.
+  csi: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+  Ground,
+  Esc,
+  Csi,
+  /// OSC, DCS and other strings, skipped until the terminator.
+  Str,
+  StrEsc,
+}
+
+/// Longest CSI sequence that is collected, longer ones are ignored.
+const MAX_CSI_LEN: usize = 32;
+
+impl Margins {
+  pub fn new(rows: u16) -> Self {
+    Margins {
+      rows,
+      main: Grid::new(rows),
+      alternate: Grid::new(rows),
+      alternate_screen: false,
+      synchronized: false,
+      cursor_style: CursorStyle::default(),
+      state: State::Ground,
+      csi: Vec::new(),
+    }
+  }
+
+  /// First and last row of the scrolling region, from 0.
+  pub fn scroll_region(&self) -> (u16, u16) {
+    let grid = self.grid();
+    (grid.top, grid.bottom)
+  }
+
+  pub fn origin_mode(&self) -> bool {
+    self.grid().origin_mode
+  }
+
+  pub fn synchronized(&self) -> bool {
+    self.synchronized
+  }
+
+  pub fn cursor_style(&self) -> CursorStyle {
+    self.cursor_style
+  }
+
+  /// Same as resizing a vt100 screen: a region that ended at the last row
+  /// keeps ending there.
+  pub fn set_rows(&mut self, rows: u16) {
+    for grid in [&mut self.main, &mut self.alternate] {
+      if grid.bottom == self.rows.saturating_sub(1) {
+        grid.bottom = rows.saturating_sub(1);
+      }
+      if grid.bottom >= rows {
+        grid.bottom = rows.saturating_sub(1);
+      }
+      if grid.bottom < grid.top {
+        grid.top = 0;
+      }
+    }
+    self.rows = rows;
+  }
+
+  pub fn scan(&mut self, bytes: &[u8]) {
+    for byte in bytes {
+      // ESC inside of a string ends it, ESC \ is just the terminator.
+      if matches!(self.state, State::StrEsc) && *byte != b'\\' {
+        self.state = State::Esc;
+      }
+      self.state = match (self.state, *byte) {
+        // CAN and SUB cancel a sequence.
+        (_, 0x18 | 0x1a) => State::Ground,
+        (State::Ground, 0x1b) => State::Esc,
+        (State::Ground, _) => State::Ground,
+        (State::Esc, b'[') => {
+          self.csi.clear();
+          State::Csi
+        }
+        (State::Esc, b']' | b'P' | b'X' | b'^' | b'_') => State::Str,
+        (State::Esc, b'7') => {
+          let grid = self.grid_mut();
+          grid.saved_origin_mode = grid.origin_mode;
+          State::Ground
+        }
+        (State::Esc, b'8') => {
+          let grid = self.grid_mut();
+          grid.origin_mode = grid.saved_origin_mode;
+          State::Ground
+        }
+        (State::Esc, b'c') => {
+          *self = Margins::new(self.rows);
+          State::Ground
+        }
+        (State::Esc, 0x1b) => State::Esc,
+        // Intermediates of other ESC sequences, e.g. charset selection.
+        (State::Esc, 0x20..=0x2f) => State::Esc,
+        (State::Esc, _) => State::Ground,
+        (State::Csi, 0x1b) => State::Esc,
+        (State::Csi, 0x20..=0x3f) => {
+          self.csi.push(*byte);
+          State::Csi
+        }
+        (State::Csi, 0x40..=0x7e) => {
+          if self.csi.len() <= MAX_CSI_LEN {
+            let csi = std::mem::take(&mut self.csi);
+            self.csi_dispatch(&csi, *byte);
+          }
+          State::Ground
+        }
+        // C0 controls are executed inside of CSI, the rest is ignored.
+        (State::Csi, _) => State::Csi,
+        (State::Str, 0x07) => State::Ground,
+        (State::Str, 0x1b) => State::StrEsc,
+        (State::Str, _) => State::Str,
+        (State::StrEsc, _) => State::Ground,
+      };
+    }
+  }
+
+  fn csi_dispatch(&mut self, csi: &[u8], action: u8) {
+    let (private, params) = match csi.first() {
+      Some(b'?') => (true, &csi[1..]),
+      _ => (false, csi),
+    };
+    if let (false, Some(param), b'q') =
+      (private, csi.strip_suffix(b" "), action)
+    {
+      self.set_cursor_style(param);
+      return;
+    }
+    if params
+      .iter()
+      .any(|b| !matches!(b, b'0'..=b'9' | b';' | b':'))
+    {
+      return;
+    }
+    let params: Vec<u16> = std::str::from_utf8(params)
+      .unwrap_or_default()
+      .split(';')
+      .map(|param| {
+        let param = param.split(':').next().unwrap_or_default();
+        param
+          .parse::<u32>()
+          .map_or(0, |n| n.min(u16::MAX as u32) as u16)
+      })
+      .collect();
+
+    match (private, action) {
+      (false, b'r') => {
+        let rows = self.rows;
+        let top = params.first().copied().filter(|n| *n > 0).unwrap_or(1);
+        let bottom = params.get(1).copied().filter(|n| *n > 0).unwrap_or(rows);
+        let (top, bottom) = (top - 1, (bottom - 1).min(rows.saturating_sub(1)));
+        let grid = self.grid_mut();
+        if top < bottom {
+          grid.top = top;
+          grid.bottom = bottom;
+        } else {
+          grid.top = 0;
+          grid.bottom = rows.saturating_sub(1);
+        }
+      }
+      (true, b'h' | b'l') => {
+        let set = action == b'h';
+        for param in params {
+          match param {
+            6 => self.grid_mut().origin_mode = set,
+            47 => self.alternate_screen = set,
+            2026 => self.synchronized = set,
+            1049 if set => {
+              self.main.saved_origin_mode = self.main.origin_mode;
+              self.alternate = Grid::new(self.rows);
+              self.alternate_screen = true;
+            }
+            1049 => {
+              self.alternate_screen = false;
+              self.main.origin_mode = self.main.saved_origin_mode;
+            }
+            _ => (),
+          }
+        }
+      }
+      _ => (),
+    }
+  }
+
+  /// DECSCUSR, invalid styles are ignored.
+  fn set_cursor_style(&mut self, param: &[u8]) {
+    let param = match std::str::from_utf8(param).unwrap_or("x") {
+      "" => 0,
+      param => match param.parse::<u16>() {
+        Ok(param) => param,
+        Err(_) => return,
+      },
+    };
+    let shape = match param {
+      0..=2 => CursorShape::Block,
+      3 | 4 => CursorShape::Underline,
+      5 | 6 => CursorShape::Bar,
+      _ => return,
+    };
+    // 0 is the default style, a blinking block.
+    let blink = param == 0 || param % 2 == 1;
+    self.cursor_style = CursorStyle { shape, blink };
+  }
+
+  fn grid(&self) -> &Grid {
+    if self.alternate_screen {
+      &self.alternate
+    } else {
+      &self.main
+    }
+  }
+
+  fn grid_mut(&mut self) -> &mut Grid {
+    if self.alternate_screen {
+      &mut self.alternate
+    } else {
+      &mut self.main
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Checks the tracked state against the cursor movements of vt100: CUP to
+  /// the home position goes to the top of the region in origin mode, and CUD
+  /// stops at the bottom of the region.
+  fn check_with_vt100(bytes: &[u8]) -> Margins {
+    let mut margins = Margins::new(24);
+    margins.scan(bytes);
+
+    let mut parser = vt100::Parser::new(24, 80, 0);
+    parser.process(bytes);
+    let (top, bottom) = margins.scroll_region();
+    let origin_mode = margins.origin_mode();
+
+    parser.process(b"\x1b[H");
+    let home = parser.screen().cursor_position().0;
+    assert_eq!(home, if origin_mode { top } else { 0 });
+
+    // In origin mode rows are relative to the region.
+    let first_row = if origin_mode { 1 } else { top + 1 };
+    parser.process(format!("\x1b[{}H\x1b[999B", first_row).as_bytes());
+    assert_eq!(parser.screen().cursor_position().0, bottom);
+    margins
+  }
+
+  #[test]
+  fn scroll_region() {
+    let margins = check_with_vt100(b"\x1b[5;20r");
+    assert_eq!(margins.scroll_region(), (4, 19));
+    assert!(!margins.origin_mode());
+
+    let margins = check_with_vt100(b"\x1b[5;20r\x1b[?6h");
+    assert!(margins.origin_mode());
+
+    // Invalid regions reset to the whole screen.
+    let margins = check_with_vt100(b"\x1b[5;20r\x1b[20;5r");
+    assert_eq!(margins.scroll_region(), (0, 23));
+    let margins = check_with_vt100(b"\x1b[3;99r");
+    assert_eq!(margins.scroll_region(), (2, 23));
+    let margins = check_with_vt100(b"\x1b[5;20r\x1b[r");
+    assert_eq!(margins.scroll_region(), (0, 23));
+
+    // Not DECSTBM.
+    let margins = check_with_vt100(b"\x1b[?5;20r\x1b]0;[5;20r\x07");
+    assert_eq!(margins.scroll_region(), (0, 23));
+    // ESC ends the string and starts a sequence.
+    let margins = check_with_vt100(b"\x1b]0;title\x1b[5;20r");
+    assert_eq!(margins.scroll_region(), (4, 19));
+  }
+
+  #[test]
+  fn split_and_reset() {
+    let mut margins = Margins::new(24);
+    margins.scan(b"\x1b[5;");
+    margins.scan(b"20r\x1b[?6");
+    margins.scan(b"h");
+    assert_eq!(margins.scroll_region(), (4, 19));
+    assert!(margins.origin_mode());
+
+    margins.scan(b"\x1bc");
+    assert_eq!(margins.scroll_region(), (0, 23));
+    assert!(!margins.origin_mode());
+  }
+
+  #[test]
+  fn alternate_screen() {
+    let mut margins = Margins::new(24);
+    margins.scan(b"\x1b[?6h\x1b[?1049h\x1b[2;10r");
+    assert_eq!(margins.scroll_region(), (1, 9));
+    assert!(!margins.origin_mode());
+    margins.scan(b"\x1b[?1049l");
+    assert_eq!(margins.scroll_region(), (0, 23));
+    assert!(margins.origin_mode());
+  }
+
+  #[test]
+  fn synchronized() {
+    let mut margins = Margins::new(24);
+    margins.scan(b"\x1b[?2026h\x1b[2J");
+    assert!(margins.synchronized());
+    margins.scan(b"frame\x1b[?2026l");
+    assert!(!margins.synchronized());
+    margins.scan(b"\x1b[?2026h\x1bc");
+    assert!(!margins.synchronized());
+  }
+
+  #[test]
+  fn cursor_style() {
+    let mut margins = Margins::new(24);
+    assert_eq!(margins.cursor_style(), CursorStyle::default());
+    let style = |margins: &Margins| {
+      let style = margins.cursor_style();
+      (style.shape.as_str(), style.blink)
+    };
+    margins.scan(b"\x1b[6 q");
+    assert_eq!(style(&margins), ("bar", false));
+    margins.scan(b"\x1b[3 q");
+    assert_eq!(style(&margins), ("underline", true));
+    // Invalid, not DECSCUSR.
+    margins.scan(b"\x1b[9 q\x1b[2q\x1b[?2 q");
+    assert_eq!(style(&margins), ("underline", true));
+    margins.scan(b"\x1b[ q");
+    assert_eq!(style(&margins), ("block", true));
+    margins.scan(b"\x1b[2 q\x1bc");
+    assert_eq!(margins.cursor_style(), CursorStyle::default());
+  }
+
+  #[test]
+  fn resize() {
+    let mut margins = Margins::new(24);
+    margins.set_rows(30);
+    assert_eq!(margins.scroll_region(), (0, 29));
+    margins.scan(b"\x1b[5;20r");
+    margins.set_rows(10);
+    assert_eq!(margins.scroll_region(), (4, 9));
+    margins.set_rows(4);
+    assert_eq!(margins.scroll_region(), (0, 3));
+  }
+}
@@ -1,44 +1,108 @@
 use anyhow::{bail, Result};
-use crossterm::event::{MouseButton, MouseEvent, MouseEventKind};
+use crossterm::event::{KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
 
 pub struct MouseAction(pub MouseEvent);
 
 impl MouseAction {
   pub fn encode(&self) -> Result<String> {
-    let mut buf = String::new();
-    buf.push_str("\x1b[<");
-
-    match self.0.kind {
+    let mut code: u8 = match self.0.kind {
       MouseEventKind::Down(btn) | MouseEventKind::Up(btn) => match btn {
-        MouseButton::Left => buf.push('0'),
-        MouseButton::Right => buf.push('1'),
-        MouseButton::Middle => buf.push('2'),
+        MouseButton::Left => 0,
+        MouseButton::Right => 1,
+        MouseButton::Middle => 2,
       },
       MouseEventKind::Drag(btn) => match btn {
-        MouseButton::Left => buf.push_str("32"),
-        MouseButton::Right => buf.push_str("33"),
-        MouseButton::Middle => buf.push_str("34"),
+        MouseButton::Left => 32,
+        MouseButton::Right => 33,
+        MouseButton::Middle => 34,
       },
-      MouseEventKind::Moved => {
-        bail!("Mouse event 'moved' is not supported yet");
-      }
-      MouseEventKind::ScrollDown => buf.push_str("64"),
-      MouseEventKind::ScrollUp => buf.push_str("65"),
+      // Motion with no button held, with a button it's a drag.
+      MouseEventKind::Moved => 35,
+      MouseEventKind::ScrollDown => 64,
+      MouseEventKind::ScrollUp => 65,
+    };
+    let mods = self.0.modifiers;
+    if mods.contains(KeyModifiers::SHIFT) {
+      code += 4;
+    }
+    if mods.contains(KeyModifiers::ALT) {
+      code += 8;
+    }
+    if mods.contains(KeyModifiers::CONTROL) {
+      code += 16;
     }
-    buf.push(';');
-    buf.push_str((self.0.column + 1).to_string().as_str());
-    buf.push(';');
-    buf.push_str((self.0.row + 1).to_string().as_str());
 
-    buf.push(match self.0.kind {
+    let suffix = match self.0.kind {
       MouseEventKind::Down(_) => 'M',
       MouseEventKind::Up(_) => 'm',
       MouseEventKind::Drag(_) => 'M',
-      MouseEventKind::Moved => todo!(),
+      MouseEventKind::Moved => 'M',
       MouseEventKind::ScrollDown => 'M',
       MouseEventKind::ScrollUp => 'M',
-    });
+    };
+
+    Ok(format!(
+      "\x1b[<{};{};{}{}",
+      code,
+      self.0.column + 1,
+      self.0.row + 1,
+      suffix
+    ))
+  }
+}
+
+/// Parses the modifiers of a mouse event, e.g. `"ctrl+shift"`.
+pub fn parse_modifiers(names: &str) -> Result<KeyModifiers> {
+  let mut mods = KeyModifiers::NONE;
+  for name in names.split('+').map(str::trim) {
+    mods |= match name.to_ascii_lowercase().as_str() {
+      "shift" => KeyModifiers::SHIFT,
+      "meta" | "alt" => KeyModifiers::ALT,
+      "ctrl" | "control" => KeyModifiers::CONTROL,
+      _ => bail!("Wrong mouse modifier: \"{}\"", name),
+    };
+  }
+  Ok(mods)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(kind: MouseEventKind) -> String {
+    encode_with(kind, KeyModifiers::NONE)
+  }
+
+  fn encode_with(kind: MouseEventKind, modifiers: KeyModifiers) -> String {
+    let event = MouseEvent {
+      kind,
+      column: 4,
+      row: 2,
+      modifiers,
+    };
+    MouseAction(event).encode().unwrap()
+  }
+
+  #[test]
+  fn encode_motion() {
+    assert_eq!(encode(MouseEventKind::Moved), "\x1b[<35;5;3M");
+    assert_eq!(
+      encode(MouseEventKind::Drag(MouseButton::Right)),
+      "\x1b[<33;5;3M"
+    );
+    assert_eq!(
+      encode(MouseEventKind::Up(MouseButton::Left)),
+      "\x1b[<0;5;3m"
+    );
+  }
 
-    Ok(buf)
+  #[test]
+  fn encode_modifiers() {
+    let down = MouseEventKind::Down(MouseButton::Left);
+    let mods = parse_modifiers("ctrl+shift").unwrap();
+    assert_eq!(encode_with(down, mods), "\x1b[<20;5;3M");
+    let mods = parse_modifiers("Meta").unwrap();
+    assert_eq!(encode_with(down, mods), "\x1b[<8;5;3M");
+    assert!(parse_modifiers("ctrl+hyper").is_err());
   }
 }
@@ -0,0 +1,271 @@
+use crate::dump_png::{Palette, DEF_BG, DEF_FG};
+
+/// Longest OSC sequence that is collected, enough to redefine the whole
+/// palette at once. Longer sequences (e.g. clipboard contents) are skipped.
+const MAX_OSC_LEN: usize = 8 * 1024;
+
+/// Finds OSC sequences in the output, including ones split between reads.
+#[derive(Default)]
+pub struct OscScanner {
+  state: State,
+  body: Vec<u8>,
+}
+
+#[derive(Default)]
+enum State {
+  #[default]
+  Ground,
+  Esc,
+  Osc,
+  /// ESC inside of OSC, the start of the ST terminator.
+  OscEsc,
+}
+
+/// A complete OSC sequence.
+pub struct Osc {
+  /// Text between `ESC ]` and the terminator.
+  pub body: Vec<u8>,
+  /// BEL or ST, replies use the same one.
+  pub terminator: &'static [u8],
+}
+
+impl OscScanner {
+  pub fn scan(&mut self, bytes: &[u8]) -> Vec<Osc> {
+    let mut found = Vec::new();
+    for byte in bytes {
+      self.state = match (&self.state, *byte) {
+        (State::Ground, 0x1b) => State::Esc,
+        (State::Ground, _) => State::Ground,
+        (State::Esc, b']') => {
+          self.body.clear();
+          State::Osc
+        }
+        (State::Esc, 0x1b) => State::Esc,
+        (State::Esc, _) => State::Ground,
+        (State::Osc, 0x07) => {
+          found.push(self.finish(b"\x07"));
+          State::Ground
+        }
+        (State::Osc, 0x1b) => State::OscEsc,
+        // CAN and SUB cancel the sequence.
+        (State::Osc, 0x18 | 0x1a) => State::Ground,
+        (State::Osc, byte) => {
+          if self.body.len() < MAX_OSC_LEN {
+            self.body.push(byte);
+          }
+          State::Osc
+        }
+        (State::OscEsc, b'\\') => {
+          found.push(self.finish(b"\x1b\\"));
+          State::Ground
+        }
+        (State::OscEsc, b']') => {
+          self.body.clear();
+          State::Osc
+        }
+        (State::OscEsc, _) => State::Ground,
+      };
+    }
+    found.retain(|osc| osc.body.len() < MAX_OSC_LEN);
+    found
+  }
+
+  fn finish(&mut self, terminator: &'static [u8]) -> Osc {
+    Osc {
+      body: std::mem::take(&mut self.body),
+      terminator,
+    }
+  }
+}
+
+/// Reply to a query of the default foreground (OSC 10), background (OSC 11)
+/// or palette colors (OSC 4). The colors are the ones of png dumps.
+pub fn color_reply(osc: &Osc, palette: &Palette) -> Option<Vec<u8>> {
+  let body = std::str::from_utf8(&osc.body).ok()?;
+  let mut parts = body.split(';');
+  let mut reply = Vec::new();
+  let mut push = |prefix: &str, rgb: [u8; 3]| {
+    reply.extend_from_slice(b"\x1b]");
+    reply.extend_from_slice(prefix.as_bytes());
+    reply.extend_from_slice(rgb_spec(rgb).as_bytes());
+    reply.extend_from_slice(osc.terminator);
+  };
+  match parts.next()? {
+    "10" if parts.next()? == "?" => push("10;", DEF_FG),
+    "11" if parts.next()? == "?" => push("11;", DEF_BG),
+    "4" => {
+      // Pairs of index and color, only `?` colors are queries.
+      while let (Some(idx), Some(color)) = (parts.next(), parts.next()) {
+        if color == "?" {
+          // Skip a bad index, the other pairs still get replies.
+          let idx: u8 = match idx.parse() {
+            Ok(idx) => idx,
+            Err(_) => continue,
+          };
+          push(&format!("4;{};", idx), palette.rgb(idx));
+        }
+      }
+    }
+    _ => return None,
+  }
+  (!reply.is_empty()).then_some(reply)
+}
+
+/// Window title set by OSC 0 (title and icon name) or OSC 2.
+pub fn title(osc: &Osc) -> Option<String> {
+  match osc.body.iter().position(|b| *b == b';') {
+    Some(1) if matches!(osc.body[0], b'0' | b'2') => {
+      Some(String::from_utf8_lossy(&osc.body[2..]).into_owned())
+    }
+    _ => None,
+  }
+}
+
+/// Applies palette changes: OSC 4 sets colors, OSC 104 resets them.
+pub fn apply_palette(osc: &Osc, palette: &mut Palette) {
+  let body = match std::str::from_utf8(&osc.body) {
+    Ok(body) => body,
+    Err(_) => return,
+  };
+  let mut parts = body.split(';');
+  match parts.next() {
+    Some("4") => {
+      while let (Some(idx), Some(color)) = (parts.next(), parts.next()) {
+        if let (Ok(idx), Some(rgb)) = (idx.parse(), parse_color(color)) {
+          palette.set(idx, rgb);
+        }
+      }
+    }
+    Some("104") => {
+      let mut indices = parts.filter(|idx| !idx.is_empty()).peekable();
+      if indices.peek().is_none() {
+        palette.reset(None);
+      }
+      for idx in indices {
+        if let Ok(idx) = idx.parse() {
+          palette.reset(Some(idx));
+        }
+      }
+    }
+    _ => (),
+  }
+}
+
+/// Parses `rgb:r/g/b` with 1 to 4 hex digits per channel, or `#rgb` with the
+/// same number of digits for each channel. Color names aren't supported.
+pub fn parse_color(spec: &str) -> Option<[u8; 3]> {
+  let channels: Vec<&str> = if let Some(rgb) = spec.strip_prefix("rgb:") {
+    rgb.split('/').collect()
+  } else {
+    let hex = spec.strip_prefix('#')?;
+    if hex.is_empty() || hex.len() % 3 != 0 {
+      return None;
+    }
+    let len = hex.len() / 3;
+    (0..3)
+      .map(|i| hex.get(i * len..(i + 1) * len))
+      .collect::<Option<_>>()?
+  };
+  let mut rgb = [0; 3];
+  if channels.len() != 3 {
+    return None;
+  }
+  for (value, channel) in rgb.iter_mut().zip(channels) {
+    if channel.is_empty() || channel.len() > 4 {
+      return None;
+    }
+    let n = u32::from_str_radix(channel, 16).ok()?;
+    let max = (1u32 << (4 * channel.len())) - 1;
+    *value = (n * 255 / max) as u8;
+  }
+  Some(rgb)
+}
+
+/// `rgb:rrrr/gggg/bbbb` as xterm replies.
+fn rgb_spec([r, g, b]: [u8; 3]) -> String {
+  format!(
+    "rgb:{:04x}/{:04x}/{:04x}",
+    r as u16 * 257,
+    g as u16 * 257,
+    b as u16 * 257
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn replies(scanner: &mut OscScanner, bytes: &[u8]) -> Vec<Vec<u8>> {
+    let palette = Palette::default();
+    let osc = scanner.scan(bytes);
+    osc
+      .iter()
+      .filter_map(|osc| color_reply(osc, &palette))
+      .collect()
+  }
+
+  #[test]
+  fn color_queries() {
+    let mut scanner = OscScanner::default();
+    assert_eq!(
+      replies(&mut scanner, b"a\x1b]11;?\x07b"),
+      [b"\x1b]11;rgb:0a0a/0a0a/3232\x07".to_vec()]
+    );
+    assert_eq!(
+      replies(&mut scanner, b"\x1b]10;?\x1b\\"),
+      [b"\x1b]10;rgb:f0f0/f0f0/f0f0\x1b\\".to_vec()]
+    );
+    assert_eq!(
+      replies(&mut scanner, b"\x1b]4;1;?;2;#00ff00;9;?\x07"),
+      [
+        b"\x1b]4;1;rgb:cdcd/0000/0000\x07\x1b]4;9;rgb:ffff/0000/0000\x07"
+          .to_vec()
+      ]
+    );
+    assert_eq!(
+      replies(&mut scanner, b"\x1b]4;1;?;x;?;300;?\x07"),
+      [b"\x1b]4;1;rgb:cdcd/0000/0000\x07".to_vec()]
+    );
+    assert!(replies(&mut scanner, b"\x1b]4;x;?\x07").is_empty());
+
+    // Split between reads.
+    assert!(replies(&mut scanner, b"\x1b]1").is_empty());
+    assert_eq!(replies(&mut scanner, b"1;?\x07").len(), 1);
+
+    // Not queries.
+    assert!(replies(&mut scanner, b"\x1b]11;#000000\x07").is_empty());
+    assert!(replies(&mut scanner, b"\x1b]0;title\x07").is_empty());
+    let long = format!("\x1b]11;?{}\x07", "x".repeat(MAX_OSC_LEN));
+    assert!(replies(&mut scanner, long.as_bytes()).is_empty());
+  }
+
+  #[test]
+  fn palette() {
+    let mut scanner = OscScanner::default();
+    let mut palette = Palette::default();
+    let mut apply = |bytes: &[u8]| {
+      for osc in scanner.scan(bytes) {
+        apply_palette(&osc, &mut palette);
+      }
+      [palette.rgb(1), palette.rgb(2), palette.rgb(3)]
+    };
+    let standard = apply(b"");
+
+    assert_eq!(
+      apply(b"\x1b]4;1;rgb:ff/80/00;2;#123456;3;?\x07"),
+      [[255, 128, 0], [0x12, 0x34, 0x56], standard[2]]
+    );
+    assert_eq!(apply(b"\x1b]4;3;rgb:ffff/0/8\x1b\\")[2], [255, 0, 136]);
+    // Invalid colors are ignored.
+    assert_eq!(
+      apply(b"\x1b]4;3;red;1;#12345\x07")[..2],
+      [[255, 128, 0], [0x12, 0x34, 0x56]]
+    );
+
+    assert_eq!(
+      apply(b"\x1b]104;2\x07"),
+      [[255, 128, 0], standard[1], [255, 0, 136]]
+    );
+    assert_eq!(apply(b"\x1b]104\x07"), standard);
+  }
+}
@@ -1,39 +1,174 @@
-use std::{collections::HashMap, io::Write, sync::Arc, time::Duration};
+use std::{
+  collections::HashMap,
+  io::Write,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use crossterm::event::{KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
-use mlua::{Lua, LuaSerdeExt, UserData, Value};
+use mlua::{Lua, LuaSerdeExt, UserData, Value, Variadic};
 use portable_pty::{ChildKiller, MasterPty, PtySize};
 use serde::Deserialize;
+use tokio::sync::oneshot;
 
 use crate::{
-  dump_png::dump_png,
-  dump_txt::dump_txt,
+  clock,
+  dump_ansi::dump_ansi,
+  dump_html::dump_html,
+  dump_json::dump_json,
+  dump_png::{self, dump_png, FontFiles, Renderer, Theme},
+  dump_txt::{dump_txt, LineEnding, Unprintable},
   encode_term::{encode_key, KeyCodeEncodeModes},
   key::Key,
   lua_utils::to_lua_err,
-  mouse::MouseAction,
+  mouse::{parse_modifiers, MouseAction},
+  profile,
+  trace::{self, TraceMode},
+  vt::{strip_escapes, Vt, TAB_WIDTH},
 };
 
+/// Ctrl-D, the default VEOF character.
+const EOT: u8 = 0x04;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Killers of the running processes by proc id. They are kept apart from
+/// `Proc`, so the processes can be killed without locking procs that may be
+/// in use by the script.
+static RUNNING: Mutex<Vec<(usize, Box<dyn ChildKiller + Send + Sync>)>> =
+  Mutex::new(Vec::new());
+
+/// Directory of the running script. Relative `cwd` of processes is resolved
+/// against it.
+static SCRIPT_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub fn set_script_dir(dir: Option<PathBuf>) {
+  *SCRIPT_DIR.lock().unwrap_or_else(|e| e.into_inner()) = dir;
+}
+
+/// Set with `--sandbox`: methods writing files fail.
+static SANDBOX: AtomicBool = AtomicBool::new(false);
+
+pub fn set_sandbox(enabled: bool) {
+  SANDBOX.store(enabled, Ordering::Relaxed);
+}
+
+fn check_sandbox(method: &str) -> mlua::Result<()> {
+  if SANDBOX.load(Ordering::Relaxed) {
+    return Err(mlua::Error::external(format!(
+      "{}(): Writing files isn't allowed in the sandbox",
+      method
+    )));
+  }
+  Ok(())
+}
+
+/// Delay after every `send_key()`/`send_str()` in milliseconds.
+static INPUT_DELAY: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_input_delay(millis: u64) {
+  INPUT_DELAY.store(millis, Ordering::Relaxed);
+}
+
+async fn input_delay() {
+  let millis = INPUT_DELAY.load(Ordering::Relaxed);
+  if millis > 0 {
+    tokio::time::sleep(Duration::from_millis(millis)).await;
+  }
+}
+
+/// Kills all processes that are still running.
+pub fn kill_all() {
+  let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+  for (id, killer) in running.iter_mut() {
+    log::info!("proc-{}: kill()", id);
+    if let Err(err) = killer.kill() {
+      log::warn!("proc-{}: Failed to kill: {}", id, err);
+    }
+  }
+}
+
 pub struct Proc {
+  /// Unique id of the process within a virterm run. Starts from 1.
+  pub id: usize,
+  pub name: Option<String>,
+  /// `None` for a screen created with `vt.new_screen()`.
+  pub child: Option<Child>,
+  pub started: Instant,
+  /// Last byte written to the process. Tells `close_input()` whether a line
+  /// is pending.
+  pub last_input: Option<u8>,
+  /// Prompt of a shell started with `vt.shell()`.
+  pub prompt: Option<String>,
+
+  pub vt: Arc<std::sync::Mutex<Vt>>,
+}
+
+/// Process running in the pty of a `Proc`.
+pub struct Child {
   pub pid: i32,
+  /// Path of the pty slave, e.g. `/dev/pts/5`.
+  pub tty_name: Option<String>,
   pub master: Box<dyn MasterPty + Send>,
+  input: std::sync::mpsc::Sender<Input>,
   pub killer: Box<dyn ChildKiller + Send + Sync>,
-  pub wait:
-    Option<tokio::sync::oneshot::Receiver<Result<portable_pty::ExitStatus>>>,
-
-  pub vt: Arc<std::sync::Mutex<vt100::Parser>>,
+  /// Exit status, `None` while the process is running.
+  pub exit: ExitReceiver,
+  /// Becomes `true` when the reader hits the end of the pty output, when all
+  /// the processes holding the pty slave closed it.
+  pub eof: tokio::sync::watch::Receiver<bool>,
+  /// Tells the reader thread to stop when the proc is dropped, checked after
+  /// every read.
+  shutdown: Arc<AtomicBool>,
 }
 
+/// Exit status or the error of waiting for the process.
+pub type ExitResult = Result<portable_pty::ExitStatus, String>;
+
+type ExitReceiver = tokio::sync::watch::Receiver<Option<ExitResult>>;
+
 #[derive(Debug, Deserialize)]
 pub struct ProcConfig {
   #[serde(default = "default_width")]
   pub width: u16,
   #[serde(default = "default_height")]
   pub height: u16,
+  /// Number of lines kept in the scrollback, 0 keeps none.
+  #[serde(default = "default_scrollback")]
+  pub scrollback: usize,
   pub cwd: Option<String>,
-  pub env: Option<HashMap<String, Option<String>>>,
+  /// What a relative `cwd` is relative to.
+  #[serde(default)]
+  pub cwd_base: CwdBase,
+  pub env: Option<HashMap<String, Option<EnvValue>>>,
   pub clear_env: Option<bool>,
+  /// Name used in logs instead of the proc id.
+  pub name: Option<String>,
+  /// KiB of the raw output to keep for `raw_tail()`. Off by default.
+  #[serde(default)]
+  pub raw_buffer: usize,
+  /// Keep the sent input in the raw buffer too.
+  #[serde(default)]
+  pub raw_input: bool,
+  /// Reply to queries of the default and palette colors.
+  #[serde(default = "default_true")]
+  pub color_replies: bool,
+  /// Niceness of the process, from -20 (highest priority) to 19.
+  pub nice: Option<i32>,
+  /// Waits see the screen only between synchronized frames (mode 2026).
+  #[serde(default)]
+  pub respect_sync: bool,
+  /// `vt.start()` returns once the screen didn't change for this long.
+  #[serde(default)]
+  pub settle_ms: u64,
+  /// Track when cells change, for `cell_changed_ago()` and `fps()`.
+  #[serde(default)]
+  pub track_cells: bool,
 }
 
 impl Default for ProcConfig {
@@ -41,11 +176,144 @@ impl Default for ProcConfig {
     Self {
       width: default_width(),
       height: default_height(),
+      scrollback: default_scrollback(),
       cwd: None,
+      cwd_base: CwdBase::default(),
       env: None,
       clear_env: None,
+      name: None,
+      raw_buffer: 0,
+      raw_input: false,
+      color_replies: true,
+      nice: None,
+      respect_sync: false,
+      settle_ms: 0,
+      track_cells: false,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShellKind {
+  #[default]
+  Bash,
+  Zsh,
+  Sh,
+}
+
+/// Prompt of `vt.shell()`, unlikely to be printed by the commands.
+const SHELL_PROMPT: &str = "virterm$ ";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ShellConfig {
+  #[serde(default)]
+  pub kind: ShellKind,
+  #[serde(default)]
+  pub login: bool,
+  /// `PS1` of the shell, `SHELL_PROMPT` by default.
+  pub prompt: Option<String>,
+  #[serde(flatten)]
+  pub proc: ProcConfig,
+}
+
+impl ShellConfig {
+  /// Interactive shell that doesn't read the startup files, so the prompt
+  /// and the settings are the same on every machine.
+  fn command(&self) -> Result<portable_pty::CommandBuilder> {
+    let (program, login, args): (_, _, &[&str]) = match self.kind {
+      ShellKind::Bash => ("bash", "--login", &["--noprofile", "--norc", "-i"]),
+      ShellKind::Zsh => ("zsh", "-l", &["-f", "-i"]),
+      ShellKind::Sh => {
+        if self.login {
+          bail!("A login sh always reads the profile files, use bash or zsh");
+        }
+        ("sh", "", &["-i"])
+      }
+    };
+    let mut cmd = portable_pty::CommandBuilder::new(program);
+    if self.login {
+      cmd.arg(login);
     }
+    cmd.args(args);
+    Ok(cmd)
+  }
+}
+
+/// Value of an environment variable in `ProcConfig.env`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum EnvValue {
+  Value(String),
+  /// `{ inherit = true }` passes the variable from the environment of
+  /// virterm, even with `clear_env`. `{ inherit = false }` removes it.
+  Inherit {
+    inherit: bool,
+  },
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CwdBase {
+  /// The directory of the running script.
+  #[default]
+  Script,
+  /// The current directory of virterm.
+  Process,
+}
+
+/// Locale of the processes, so the widths of wide chars are the same on every
+/// machine. It's set in `LANG` and `LC_ALL` unless `env` sets a locale.
+const LOCALE: &str = "C.UTF-8";
+
+/// Whether `env` sets any of the locale variables.
+fn sets_locale(env: Option<&HashMap<String, Option<EnvValue>>>) -> bool {
+  env.is_some_and(|env| env.keys().any(|k| k == "LANG" || k.starts_with("LC_")))
+}
+
+/// Resolves the working directory of a process. Without `cwd` it's the
+/// current directory of virterm.
+fn resolve_cwd(
+  cwd: Option<&str>,
+  base: CwdBase,
+  script_dir: Option<&Path>,
+) -> Result<PathBuf> {
+  let current_dir = std::env::current_dir()?;
+  let cwd = match cwd {
+    Some(cwd) => Path::new(cwd),
+    None => return Ok(current_dir),
+  };
+  let base_dir = match (base, script_dir) {
+    (CwdBase::Script, Some(script_dir)) => current_dir.join(script_dir),
+    _ => current_dir,
+  };
+  Ok(base_dir.join(cwd))
+}
+
+/// Most cells of a terminal, vt100 allocates all of them up front.
+const MAX_CELLS: usize = 1 << 20;
+
+pub fn validate_size(width: u16, height: u16) -> Result<()> {
+  if width == 0 || height == 0 {
+    bail!(
+      "Terminal size must be at least 1x1, got {}x{}",
+      width,
+      height
+    );
+  }
+  if width as usize * height as usize > MAX_CELLS {
+    bail!(
+      "Terminal size must be at most {} cells, got {}x{}",
+      MAX_CELLS,
+      width,
+      height
+    );
   }
+  Ok(())
+}
+
+fn default_true() -> bool {
+  true
 }
 
 fn default_width() -> u16 {
@@ -55,18 +323,293 @@ fn default_height() -> u16 {
   30
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ScreenConfig {
+  #[serde(default = "default_width")]
+  pub width: u16,
+  #[serde(default = "default_height")]
+  pub height: u16,
+  /// Number of lines kept in the scrollback.
+  #[serde(default = "default_scrollback")]
+  pub scrollback: usize,
+  /// See `ProcConfig::track_cells`.
+  #[serde(default)]
+  pub track_cells: bool,
+}
+
+impl Default for ScreenConfig {
+  fn default() -> Self {
+    Self {
+      width: default_width(),
+      height: default_height(),
+      scrollback: default_scrollback(),
+      track_cells: false,
+    }
+  }
+}
+
+fn default_scrollback() -> usize {
+  100
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ResizeConfig {
   pub width: u16,
   pub height: u16,
 }
 
+/// Actions of `run_plan()`: the method each of them calls and the fields of
+/// the entry passed to it. All but `opts` are required.
+const PLAN_ACTIONS: &[(&str, &str, &[&str])] = &[
+  ("send_key", "send_key", &["key", "opts"]),
+  ("send_str", "send_str", &["text"]),
+  ("wait_text", "wait_text", &["text", "opts"]),
+  ("screenshot", "dump_png", &["path", "opts"]),
+];
+
+/// Entry of a `run_plan()` plan, checked before the plan runs.
+struct PlanEntry<'lua> {
+  at_ms: u64,
+  action: &'static str,
+  method: &'static str,
+  args: Vec<Value<'lua>>,
+}
+
+impl<'lua> PlanEntry<'lua> {
+  /// `n` is the number of the entry in errors, starting from 1.
+  fn from_table(n: usize, entry: &mlua::Table<'lua>) -> mlua::Result<Self> {
+    let error = |msg: String| {
+      mlua::Error::external(format!("run_plan(): Entry {}: {}", n, msg))
+    };
+    let at_ms = match entry.get::<_, Value>("at_ms")? {
+      Value::Integer(ms) if ms >= 0 => ms as u64,
+      Value::Number(ms) if ms >= 0.0 => ms as u64,
+      _ => return Err(error("at_ms must be a non-negative number".into())),
+    };
+    let action: String = entry
+      .get::<_, Option<String>>("action")?
+      .ok_or_else(|| error("Missing action".into()))?;
+    let (action, method, fields) = PLAN_ACTIONS
+      .iter()
+      .find(|(name, _, _)| *name == action)
+      .ok_or_else(|| error(format!("Unknown action: {:?}", action)))?;
+    let mut args = Vec::new();
+    for field in fields.iter() {
+      let value: Value = entry.get(*field)?;
+      if matches!(value, Value::Nil) && *field != "opts" {
+        return Err(error(format!("{} needs {}", action, field)));
+      }
+      args.push(value);
+    }
+    Ok(PlanEntry {
+      at_ms,
+      action,
+      method,
+      args,
+    })
+  }
+}
+
+/// The error raised inside of the Lua calls that led to `err`.
+fn callback_cause(err: &mlua::Error) -> &mlua::Error {
+  match err {
+    mlua::Error::CallbackError { cause, .. } => callback_cause(cause),
+    err => err,
+  }
+}
+
+/// Rectangle of cells, `x` and `y` of the top left one start from 0.
+#[derive(Debug, Deserialize)]
+pub struct Region {
+  pub x: u16,
+  pub y: u16,
+  pub w: u16,
+  pub h: u16,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct WaitOpts {
+  pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct WaitTextOpts {
+  pub timeout: Option<u64>,
+  /// Number of occurrences to wait for.
+  pub count: Option<usize>,
+  #[serde(default)]
+  pub source: TextSource,
+  #[serde(default)]
+  pub ignore_case: bool,
+  /// Runs of whitespace match any other run of whitespace.
+  #[serde(default)]
+  pub collapse_whitespace: bool,
+  /// The text is a regular expression.
+  #[serde(default)]
+  pub regex: bool,
+}
+
+impl WaitTextOpts {
+  /// Normalizes both the searched text and the screen contents.
+  fn fold(&self, text: &str) -> String {
+    let text = if self.ignore_case {
+      text.to_lowercase()
+    } else {
+      text.to_string()
+    };
+    if !self.collapse_whitespace {
+      return text;
+    }
+    let mut folded = String::with_capacity(text.len());
+    let mut in_space = false;
+    for ch in text.chars() {
+      if ch.is_whitespace() {
+        if !in_space {
+          folded.push(' ');
+        }
+        in_space = true;
+      } else {
+        folded.push(ch);
+        in_space = false;
+      }
+    }
+    folded
+  }
+}
+
+/// Where `wait_text()` looks for the text.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextSource {
+  /// The current screen contents.
+  #[default]
+  Screen,
+  /// The output printed while waiting.
+  Stream,
+  /// The raw output kept for `raw_tail()`, including the output printed
+  /// before waiting.
+  Raw,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PressUntilOpts {
+  pub key: String,
+  pub text: String,
+  /// Most presses before giving up.
+  #[serde(default = "default_max_presses")]
+  pub max: usize,
+  /// How long the screen must not change after a press before checking.
+  #[serde(default = "default_press_settle_ms")]
+  pub settle_ms: u64,
+  /// Timeout of all the presses together. No timeout by default.
+  pub timeout: Option<u64>,
+}
+
+fn default_max_presses() -> usize {
+  20
+}
+
+fn default_press_settle_ms() -> u64 {
+  100
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SendKeyOpts {
+  /// Forces application (`true`) or normal (`false`) cursor key encoding.
+  pub app_cursor: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendLineWaitOpts {
+  /// Text printed when the process is ready for the next line.
+  pub prompt: String,
+  pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DumpPngOpts {
+  /// Render the scrollback above the screen.
+  #[serde(default)]
+  pub include_scrollback: bool,
+  /// Limit of the scrollback rows to render, the most recent ones are kept.
+  pub max_scrollback: Option<usize>,
+  /// Marker of cells with invisible chars.
+  pub unprintable: Option<Unprintable>,
+  /// Text of a band above the screen.
+  pub caption: Option<String>,
+  /// Font size in pixels.
+  pub scale: Option<f32>,
+  /// Cell size in pixels instead of the one of the font metrics.
+  pub cell_width: Option<u32>,
+  pub cell_height: Option<u32>,
+  /// Font files used instead of the bundled fonts.
+  pub font: Option<FontFiles>,
+  /// Colors used instead of the default ones.
+  pub theme: Option<Theme>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DumpTxtOpts {
+  /// Marker of invisible chars.
+  pub unprintable: Option<Unprintable>,
+  #[serde(default)]
+  pub line_ending: LineEnding,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DumpOpts {
+  /// Also write `<basename>.json`.
+  #[serde(default)]
+  pub json: bool,
+  #[serde(default)]
+  pub line_ending: LineEnding,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ContentsOpts {
+  #[serde(default)]
+  pub line_ending: LineEnding,
+  /// Include the scrollback above the screen.
+  #[serde(default)]
+  pub scrollback: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ClickParams {
   x: u16,
   y: u16,
   #[serde(default = "default_click_button")]
   button: ClickButton,
+  modifiers: Option<MouseModifiers>,
+}
+
+/// Modifiers held during a click, `"ctrl+shift"` or `{ "ctrl", "shift" }`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MouseModifiers {
+  One(String),
+  List(Vec<String>),
+}
+
+impl MouseModifiers {
+  fn parse(&self) -> Result<KeyModifiers> {
+    match self {
+      MouseModifiers::One(names) => parse_modifiers(names),
+      MouseModifiers::List(names) => {
+        names.iter().try_fold(KeyModifiers::NONE, |mods, name| {
+          Ok(mods | parse_modifiers(name)?)
+        })
+      }
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MouseMoveParams {
+  x: u16,
+  y: u16,
+  /// Button held while moving, which makes it a drag.
+  button: Option<ClickButton>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -98,6 +641,23 @@ pub enum ScrollDir {
   Down,
 }
 
+/// A proc dropped by the script (e.g. collected by the Lua GC) hangs up its
+/// process, like closing a terminal window, and its reader thread stops at
+/// the end of the pty output. The foreground process group, e.g. background
+/// jobs of a shell without job control, gets SIGHUP from the kernel when the
+/// process exits. A process that ignores SIGHUP or left the group keeps the
+/// pty slave open, and the reader blocked in `read()`, until it exits.
+impl Drop for Proc {
+  fn drop(&mut self) {
+    if let Some(child) = &mut self.child {
+      child.shutdown.store(true, Ordering::Relaxed);
+      if child.exit.borrow().is_none() {
+        let _r = child.killer.kill();
+      }
+    }
+  }
+}
+
 impl Proc {
   pub fn shell(shell: &str, cfg: &ProcConfig) -> Result<Self> {
     Self::start(portable_pty::CommandBuilder::from_shell(shell), cfg)
@@ -107,17 +667,27 @@ impl Proc {
     mut cmd: portable_pty::CommandBuilder,
     cfg: &ProcConfig,
   ) -> Result<Self> {
-    if let Some(cwd) = &cfg.cwd {
-      cmd.cwd(cwd);
-    } else {
-      cmd.cwd(std::env::current_dir()?.as_os_str());
+    validate_size(cfg.width, cfg.height)?;
+
+    let script_dir =
+      SCRIPT_DIR.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let cwd =
+      resolve_cwd(cfg.cwd.as_deref(), cfg.cwd_base, script_dir.as_deref())?;
+    cmd.cwd(cwd.as_os_str());
+    if let Some(true) = cfg.clear_env {
+      cmd.env_clear();
     }
-    match cfg.clear_env {
-      Some(true) => cmd.env_clear(),
-      _ => (),
+    if !sets_locale(cfg.env.as_ref()) {
+      cmd.env("LANG", LOCALE);
+      cmd.env("LC_ALL", LOCALE);
     }
     if let Some(env) = &cfg.env {
       for (k, v) in env {
+        let v = match v {
+          Some(EnvValue::Value(v)) => Some(v.into()),
+          Some(EnvValue::Inherit { inherit: true }) => std::env::var_os(k),
+          Some(EnvValue::Inherit { inherit: false }) | None => None,
+        };
         if let Some(v) = v {
           cmd.env(k, v);
         } else {
@@ -126,6 +696,10 @@ impl Proc {
       }
     }
 
+    if let Some(nice) = cfg.nice {
+      set_nice(&mut cmd, nice);
+    }
+
     let pair =
       portable_pty::native_pty_system().openpty(portable_pty::PtySize {
         rows: cfg.height,
@@ -134,44 +708,103 @@ impl Proc {
         pixel_height: 0,
       })?;
     let mut child = pair.slave.spawn_command(cmd)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
     let pid = child.process_id().map(|i| i as i32).unwrap_or(-1);
     let killer = child.clone_killer();
+    let tty_name = tty_name(pid);
 
-    let (wait_send, wait) = tokio::sync::oneshot::channel();
+    RUNNING
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .push((id, child.clone_killer()));
+
+    let (exit_send, exit) = tokio::sync::watch::channel(None);
     std::thread::spawn(move || {
-      let result = child.wait().map_err(anyhow::Error::from);
-      let _r = wait_send.send(result);
+      let result = child.wait().map_err(|err| err.to_string());
+      RUNNING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|(running_id, _)| *running_id != id);
+      let _r = exit_send.send(Some(result));
     });
 
-    let vt = vt100::Parser::new(cfg.height, cfg.width, 100);
+    let mut vt = Vt::new(cfg.height, cfg.width, cfg.scrollback);
+    vt.set_raw_capacity(cfg.raw_buffer * 1024);
+    vt.set_raw_input(cfg.raw_input);
+    vt.set_respect_sync(cfg.respect_sync);
+    vt.set_color_replies(cfg.color_replies);
+    if cfg.track_cells {
+      vt.track_cells();
+    }
     let vt = Arc::new(std::sync::Mutex::new(vt));
 
     let mut reader = pair.master.try_clone_reader().unwrap();
+    let mut replies_writer = pair.master.try_clone_writer()?;
+
+    // Writes block while the pty buffer is full, e.g. when the process doesn't
+    // read its input, so they are done in a separate thread in the order they
+    // were sent.
+    let (input, input_rx) = std::sync::mpsc::channel::<Input>();
+    let mut writer = pair.master.try_clone_writer()?;
+    std::thread::spawn(move || {
+      for (bytes, written) in input_rx {
+        let result = writer.write_all(&bytes).and_then(|()| writer.flush());
+        let _ = written.send(result);
+      }
+    });
 
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let (eof_send, eof) = tokio::sync::watch::channel(false);
     {
       let vt = vt.clone();
+      let shutdown = shutdown.clone();
+      let log_name = match &cfg.name {
+        Some(name) => name.clone(),
+        None => format!("proc-{}", id),
+      };
       tokio::task::spawn_blocking(move || {
         let mut buf = [0; 4 * 1024];
+        // Reads block until there is output, the loop only ends at the end of
+        // the output or on an error that a retry won't fix.
         loop {
-          match reader.read(&mut buf[..]) {
-            Ok(count) => {
-              if count > 0 {
-                vt.clone().lock().unwrap().process(&buf[..count]);
-              } else {
-                std::thread::sleep(std::time::Duration::from_millis(10));
-              }
+          let count = match reader.read(&mut buf[..]) {
+            // The pty reports EIO when the slave is closed, the reader returns
+            // it as the end of the output.
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {
+              continue
+            }
+            Err(err) => {
+              log::warn!("{}: Failed to read the output: {}", log_name, err);
+              break;
             }
-            _ => break,
+          };
+          if shutdown.load(Ordering::Relaxed) {
+            break;
           }
+          process_output(&vt, &buf[..count], &mut replies_writer, &log_name);
         }
+        let _r = eof_send.send(true);
       });
     }
 
     let proc = Proc {
-      pid,
-      master: pair.master,
-      killer,
-      wait: Some(wait),
+      id,
+      name: cfg.name.clone(),
+      child: Some(Child {
+        pid,
+        tty_name,
+        master: pair.master,
+        input,
+        killer,
+        exit,
+        eof,
+        shutdown,
+      }),
+      started: Instant::now(),
+      last_input: None,
+      prompt: None,
 
       vt,
     };
@@ -179,9 +812,166 @@ impl Proc {
     Ok(proc)
   }
 
-  pub fn send_key(&mut self, key: &Key) {
-    let application_cursor_keys =
-      self.lock_vt().unwrap().screen().application_cursor();
+  /// Starts an interactive shell with a known prompt, `TERM` and no history
+  /// file. Variables in `cfg.proc.env` override them.
+  pub fn interactive_shell(cfg: ShellConfig) -> Result<Self> {
+    let cmd = cfg.command()?;
+    let prompt = cfg.prompt.unwrap_or_else(|| SHELL_PROMPT.to_string());
+    let mut proc_cfg = cfg.proc;
+    let env = proc_cfg.env.get_or_insert_with(HashMap::new);
+    for (k, v) in [
+      ("TERM", "xterm-256color"),
+      ("PS1", prompt.as_str()),
+      ("HISTFILE", ""),
+    ] {
+      env
+        .entry(k.to_string())
+        .or_insert_with(|| Some(EnvValue::Value(v.to_string())));
+    }
+    let mut proc = Proc::start(cmd, &proc_cfg)?;
+    proc.prompt = Some(prompt);
+    Ok(proc)
+  }
+
+  /// Creates a terminal without a process. Output is fed with
+  /// `parse_output()`.
+  pub fn screen(cfg: &ScreenConfig) -> Result<Self> {
+    validate_size(cfg.width, cfg.height)?;
+    let mut vt = Vt::new(cfg.height, cfg.width, cfg.scrollback);
+    if cfg.track_cells {
+      vt.track_cells();
+    }
+    Ok(Proc {
+      id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+      name: None,
+      child: None,
+      started: Instant::now(),
+      last_input: None,
+      prompt: None,
+
+      vt: Arc::new(std::sync::Mutex::new(vt)),
+    })
+  }
+
+  pub fn child(&self) -> Result<&Child> {
+    match &self.child {
+      Some(child) => Ok(child),
+      None => bail!("{}: The screen has no process", self.log_name()),
+    }
+  }
+
+  pub fn child_mut(&mut self) -> Result<&mut Child> {
+    let log_name = self.log_name();
+    match &mut self.child {
+      Some(child) => Ok(child),
+      None => bail!("{}: The screen has no process", log_name),
+    }
+  }
+
+  /// Processes bytes as if the child had printed them.
+  pub fn parse_output(&mut self, bytes: &[u8]) {
+    let log_name = self.log_name();
+    let mut replies = Vec::new();
+    process_output(&self.vt, bytes, &mut replies, &log_name);
+    if let (Some(child), false) = (&self.child, replies.is_empty()) {
+      let (written, _) = oneshot::channel();
+      let _ = child.input.send((replies, written));
+    }
+  }
+
+  /// Name of the process for logs: either the configured name or the id.
+  pub fn log_name(&self) -> String {
+    match &self.name {
+      Some(name) => name.clone(),
+      None => format!("proc-{}", self.id),
+    }
+  }
+
+  /// Queues input to be written to the pty. `action` describes the input for
+  /// traces. Await the result to know when the input was written.
+  pub fn write_input(
+    &mut self,
+    action: &str,
+    bytes: &[u8],
+  ) -> Result<InputWritten> {
+    let input = self.child()?.input.clone();
+    let mode = trace::mode();
+    if mode != TraceMode::Off {
+      let hash = self.lock_vt()?.screen_hash();
+      log::info!(
+        target: "virterm::trace",
+        "{}: +{}ms {} bytes=\"{}\" screen={:016x}{}",
+        self.log_name(),
+        clock::elapsed(self.started).as_millis(),
+        action,
+        bytes.escape_ascii(),
+        hash,
+        if mode == TraceMode::DryRun {
+          " (dry run)"
+        } else {
+          ""
+        },
+      );
+      if mode == TraceMode::DryRun {
+        return Ok(InputWritten(None));
+      }
+    }
+    let (written, written_rx) = oneshot::channel();
+    input
+      .send((bytes.to_vec(), written))
+      .map_err(|_| anyhow!("Input writer stopped"))?;
+    self.lock_vt()?.mark_input(bytes);
+    if let Some(last) = bytes.last() {
+      self.last_input = Some(*last);
+    }
+    Ok(InputWritten(Some(written_rx)))
+  }
+
+  /// Resolves once all the queued input is written and the master is flushed.
+  pub fn flush(&mut self) -> Result<InputWritten> {
+    let (written, written_rx) = oneshot::channel();
+    self
+      .child()?
+      .input
+      .send((Vec::new(), written))
+      .map_err(|_| anyhow!("Input writer stopped"))?;
+    Ok(InputWritten(Some(written_rx)))
+  }
+
+  /// Signals end of input. Unlike a pipe, a pty can't be closed for writing
+  /// only (closing the master hangs up the terminal), so EOF is sent the way
+  /// a user would: Ctrl-D at the start of a line. If a line is pending, the
+  /// first Ctrl-D only submits it and the second one signals EOF.
+  ///
+  /// Works for programs that keep the terminal in canonical mode. In raw
+  /// mode Ctrl-D is just a byte that the program interprets itself.
+  pub fn close_input(&mut self) -> Result<InputWritten> {
+    let bytes: &[u8] = match self.last_input {
+      None | Some(b'\n') | Some(b'\r') | Some(EOT) => &[EOT],
+      Some(_) => &[EOT, EOT],
+    };
+    self.write_input("close_input()", bytes)
+  }
+
+  /// Pastes bytes like a terminal does: in one write, framed with
+  /// `ESC [ 200 ~` and `ESC [ 201 ~` if the app enabled bracketed paste.
+  pub fn paste(&mut self, bytes: &[u8]) -> Result<InputWritten> {
+    let bracketed = self.lock_vt()?.screen().bracketed_paste();
+    let action = format!("paste({} bytes)", bytes.len());
+    self.write_input(action.as_str(), &paste_frame(bytes, bracketed))
+  }
+
+  /// Sends a key. Arrow keys are encoded for the cursor key mode set by the
+  /// app, unless `app_cursor` overrides it.
+  pub fn send_key(
+    &mut self,
+    key: &Key,
+    app_cursor: Option<bool>,
+  ) -> Result<InputWritten> {
+    let application_cursor_keys = match app_cursor {
+      Some(app_cursor) => app_cursor,
+      None => self.lock_vt().unwrap().screen().application_cursor(),
+    };
     let encoder = encode_key(
       key,
       KeyCodeEncodeModes {
@@ -192,58 +982,108 @@ impl Proc {
     );
     match encoder {
       Ok(encoder) => {
-        self.master.write_all(encoder.as_bytes()).unwrap();
+        let action = format!("send_key({})", key);
+        self.write_input(action.as_str(), encoder.as_bytes())
       }
       Err(_) => {
-        log::warn!("Failed to encode key: {}", key.to_string());
+        log::warn!("{}: Failed to encode key: {}", self.log_name(), key);
+        Ok(InputWritten(None))
       }
     }
   }
 
-  pub fn send_mouse(&mut self, mouse: &MouseAction) -> Result<()> {
-    self.master.write_all(mouse.encode()?.as_bytes())?;
-    Ok(())
+  pub fn send_mouse(&mut self, mouse: &MouseAction) -> Result<InputWritten> {
+    let event = &mouse.0;
+    let action = format!(
+      "mouse({:?}, x={}, y={}, mods={:?})",
+      event.kind, event.column, event.row, event.modifiers
+    );
+    self.write_input(action.as_str(), mouse.encode()?.as_bytes())
   }
 
   #[cfg(windows)]
-  pub fn send_signal(&mut self, _sig: libc::c_int) {
-    ()
+  pub fn send_signal(&mut self, _sig: libc::c_int) -> Result<()> {
+    self.child()?;
+    Ok(())
   }
 
   #[cfg(not(windows))]
-  pub fn send_signal(&mut self, sig: libc::c_int) {
-    unsafe { libc::kill(self.pid, sig) };
+  pub fn send_signal(&mut self, sig: libc::c_int) -> Result<()> {
+    unsafe { libc::kill(self.child()?.pid, sig) };
+    Ok(())
+  }
+
+  /// Exit status, `None` while the process is running.
+  pub fn exit_status(&self) -> Result<Option<ExitResult>> {
+    Ok(self.child()?.exit.borrow().clone())
+  }
+
+  /// Waits until the process exits. Can be called any number of times, also
+  /// after the process has exited.
+  pub async fn wait(
+    name: &str,
+    mut exit: ExitReceiver,
+  ) -> Result<portable_pty::ExitStatus> {
+    let result = loop {
+      if let Some(result) = exit.borrow().clone() {
+        break result;
+      }
+      if exit.changed().await.is_err() {
+        bail!("{}: wait(): Exit status is lost", name);
+      }
+    };
+    match &result {
+      Ok(status) if status.success() => {
+        log::info!("{}: Process returned ok", name)
+      }
+      Ok(_) => log::info!("{}: Process returned error", name),
+      Err(err) => log::info!("{}: wait(): Error: {}", name, err),
+    }
+    result.map_err(anyhow::Error::msg)
   }
 
-  pub async fn wait(&mut self) -> Result<()> {
-    if let Some(wait) = self.wait.take() {
-      match wait.await? {
-        Ok(status) if status.success() => {
-          log::info!("Process returned ok")
+  /// Waits until the reader hits the end of the pty output. Unlike `wait()`,
+  /// it waits for the grandchildren that inherited the pty too.
+  pub async fn wait_eof(
+    mut eof: tokio::sync::watch::Receiver<bool>,
+    timeout: Option<u64>,
+  ) -> Result<()> {
+    let timeout =
+      Duration::from_millis(timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT));
+    tokio::time::timeout(timeout, async {
+      while !*eof.borrow() {
+        if eof.changed().await.is_err() {
+          bail!("wait_eof(): The reader is stopped");
         }
-        Ok(_) => log::info!("Process returned error"),
-        Err(err) => log::info!("wait(): Error: {}", err),
       }
       Ok(())
-    } else {
-      bail!("Can't wait the process more than once");
-    }
+    })
+    .await?
   }
 
-  pub async fn resize(&mut self, opts: ResizeConfig) -> Result<()> {
-    self.lock_vt()?.set_size(opts.height, opts.width);
-    self.master.resize(PtySize {
-      cols: opts.width,
-      rows: opts.height,
-      pixel_width: 0,
-      pixel_height: 0,
-    })?;
-    Ok(())
+  /// Returns `false` if the terminal already has the size. The pty isn't
+  /// resized then, so the process doesn't get a spurious SIGWINCH.
+  pub fn resize(&mut self, opts: ResizeConfig) -> Result<bool> {
+    validate_size(opts.width, opts.height)?;
+    {
+      let mut vt = self.lock_vt()?;
+      if vt.screen().size() == (opts.height, opts.width) {
+        return Ok(false);
+      }
+      vt.set_size(opts.height, opts.width);
+    }
+    if let Some(child) = &self.child {
+      child.master.resize(PtySize {
+        cols: opts.width,
+        rows: opts.height,
+        pixel_width: 0,
+        pixel_height: 0,
+      })?;
+    }
+    Ok(true)
   }
 
-  fn lock_vt(
-    &self,
-  ) -> Result<std::sync::MutexGuard<vt100::Parser>, mlua::Error> {
+  pub fn lock_vt(&self) -> Result<std::sync::MutexGuard<'_, Vt>, mlua::Error> {
     self
       .vt
       .lock()
@@ -259,7 +1099,7 @@ impl LuaProc {
     LuaProc(Arc::new(std::sync::Mutex::new(proc)))
   }
 
-  fn lock(&self) -> Result<std::sync::MutexGuard<Proc>, mlua::Error> {
+  pub fn lock(&self) -> Result<std::sync::MutexGuard<'_, Proc>, mlua::Error> {
     self
       .0
       .lock()
@@ -271,12 +1111,39 @@ impl UserData for LuaProc {
   fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(_fields: &mut F) {}
 
   fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+    let mut methods = profile::TimedMethods::new(methods, "proc");
+
+    // id()
+    methods.add_method("id", |_, proc, ()| {
+      let id = proc.lock()?.id;
+      Ok(id)
+    });
+
+    // name()
+    methods.add_method("name", |_, proc, ()| {
+      let name = proc.lock()?.name.clone();
+      Ok(name)
+    });
+
     // pid()
     methods.add_method("pid", |_, proc, ()| {
-      let pid = proc.lock()?.pid;
+      let pid = proc.lock()?.child().map_err(to_lua_err)?.pid;
       Ok(pid)
     });
 
+    // tty_name()
+    methods.add_method("tty_name", |_, proc, ()| {
+      let proc = proc.lock()?;
+      let tty_name = proc.child().map_err(to_lua_err)?.tty_name.clone();
+      Ok(tty_name)
+    });
+
+    // child_pids()
+    methods.add_method("child_pids", |_, proc, ()| {
+      let pid = proc.lock()?.child().map_err(to_lua_err)?.pid;
+      Ok(child_pids(pid))
+    });
+
     // cell()
     #[derive(Deserialize)]
     struct CellOpts {
@@ -297,79 +1164,580 @@ impl UserData for LuaProc {
       };
       let info = lua.create_table()?;
       info.set("content", cell.contents())?;
-      info.set("fg", from_vt_color(lua, cell.fgcolor())?)?;
-      info.set("bg", from_vt_color(lua, cell.bgcolor())?)?;
-      info.set("bold", cell.bold())?;
-      info.set("italic", cell.italic())?;
-      info.set("underline", cell.underline())?;
-      info.set("inverse", cell.inverse())?;
+      set_cell_style(lua, &info, &cell)?;
       info.set("wide", cell.is_wide())?;
       Ok(Value::Table(info))
     });
 
-    // contents()
-    methods.add_method("contents", |_, proc, ()| {
-      let contents = proc.lock()?.lock_vt()?.screen().contents();
-      Ok(contents)
+    // prompt()
+    methods.add_method("prompt", |_, proc, ()| Ok(proc.lock()?.prompt.clone()));
+
+    // tab_width()
+    methods.add_method("tab_width", |_, _, ()| Ok(TAB_WIDTH));
+
+    // cell_changed_ago(x, y)
+    methods.add_method("cell_changed_ago", |_, proc, (x, y): (u16, u16)| {
+      let proc = proc.lock()?;
+      let vt = proc.lock_vt()?;
+      if !vt.tracks_cells() {
+        return Err(mlua::Error::external(
+          "cell_changed_ago(): Cells aren't tracked, set `track_cells` in vt.start()",
+        ));
+      }
+      Ok(vt.cell_changed_ago(y, x).map(|ago| ago.as_millis() as u64))
     });
 
-    // contents_hex()
-    methods.add_method("contents_hex", |_, proc, ()| {
-      let contents = proc.lock()?.lock_vt()?.screen().contents();
-      let mut buf = String::new();
-      for ch in contents.chars() {
-        if ch == '\n' || ch == '\r' {
-          buf.push(ch);
-        } else {
-          let num = ch as u32;
-          let s = if num <= 255 {
-            format!(" {:02x}", num)
-          } else {
-            format!(" {:x}", num)
-          };
-          buf.push_str(s.as_str());
-        }
+    // fps()
+    methods.add_method("fps", |_, proc, ()| {
+      let proc = proc.lock()?;
+      let mut vt = proc.lock_vt()?;
+      if !vt.tracks_cells() {
+        return Err(mlua::Error::external(
+          "fps(): Cells aren't tracked, set `track_cells` in vt.start()",
+        ));
       }
-      Ok(buf)
+      Ok(vt.fps())
     });
 
-    // send_str
-    methods.add_method("send_str", |_, proc, str: String| {
-      log::info!("send_str(): {}", str);
-      let mut proc = proc.lock()?;
-      proc.master.write_all(str.as_bytes()).map_err(to_lua_err)?;
+    // watch_region({x, y, w, h})
+    methods.add_method("watch_region", |lua, proc, region: Value| {
+      let region: Region = lua.from_value(region)?;
+      if region.w == 0 || region.h == 0 {
+        return Err(mlua::Error::external(
+          "watch_region(): w and h must be positive",
+        ));
+      }
+      let proc = proc.lock()?;
+      log::info!("{}: watch_region(): {:?}", proc.log_name(), region);
+      let rows = region.y..region.y.saturating_add(region.h);
+      let cols = region.x..region.x.saturating_add(region.w);
+      let id = proc.lock_vt()?.add_watch(rows, cols);
+      Ok(id)
+    });
+
+    // assert_region_unchanged(handle)
+    methods.add_method("assert_region_unchanged", |_, proc, id: usize| {
+      let proc = proc.lock()?;
+      let changes = match proc.lock_vt()?.watch_changes(id) {
+        Some(changes) => changes,
+        None => {
+          return Err(mlua::Error::external(format!(
+            "assert_region_unchanged(): Unknown region handle: {}",
+            id
+          )))
+        }
+      };
+      if changes.is_empty() {
+        return Ok(());
+      }
+      let cells: Vec<_> = changes
+        .iter()
+        .take(REGION_CHANGES_LIMIT)
+        .map(|(row, col)| format!("x={} y={}", col, row))
+        .collect();
+      let more = match changes.len().saturating_sub(REGION_CHANGES_LIMIT) {
+        0 => String::new(),
+        more => format!(" and {} more", more),
+      };
+      Err(mlua::Error::external(format!(
+        "assert_region_unchanged(): {} cells changed: {}{}",
+        changes.len(),
+        cells.join(", "),
+        more
+      )))
+    });
+
+    // line_runs(y)
+    methods.add_method("line_runs", |lua, proc, y: u16| {
+      let proc = proc.lock()?;
+      let vt = proc.lock_vt()?;
+      let screen = vt.screen();
+      let (rows, cols) = screen.size();
+      if y >= rows {
+        return Ok(None);
+      }
+      let mut runs = Vec::new();
+      let mut run: Option<(u16, vt100::Cell, String)> = None;
+      for x in 0..cols {
+        let cell = match screen.cell(y, x) {
+          Some(cell) if !cell.is_wide_continuation() => cell,
+          _ => continue,
+        };
+        let text = match cell.contents() {
+          contents if contents.is_empty() => " ".to_string(),
+          contents => contents,
+        };
+        match &mut run {
+          Some((_, first, run_text)) if same_style(first, cell) => {
+            run_text.push_str(&text);
+          }
+          _ => {
+            if let Some(run) = run.replace((x, cell.clone(), text)) {
+              runs.push(line_run(lua, run)?);
+            }
+          }
+        }
+      }
+      if let Some(run) = run {
+        runs.push(line_run(lua, run)?);
+      }
+      Ok(Some(runs))
+    });
+
+    // scroll_region()
+    methods.add_method("scroll_region", |lua, proc, ()| {
+      let (top, bottom) = proc.lock()?.lock_vt()?.scroll_region();
+      let region = lua.create_table()?;
+      region.set("top", top)?;
+      region.set("bottom", bottom)?;
+      Ok(region)
+    });
+
+    // cursor()
+    methods.add_method("cursor", |lua, proc, ()| {
+      let proc = proc.lock()?;
+      let vt = proc.lock_vt()?;
+      let screen = vt.screen();
+      let (y, x) = screen.cursor_position();
+      let hidden = screen.hide_cursor();
+      let cursor = lua.create_table()?;
+      cursor.set("x", x)?;
+      cursor.set("y", y)?;
+      cursor.set("hidden", hidden)?;
+      cursor.set("visible", !hidden)?;
+      Ok(cursor)
+    });
+
+    // cursor_style()
+    methods.add_method("cursor_style", |lua, proc, ()| {
+      let style = proc.lock()?.lock_vt()?.cursor_style();
+      let info = lua.create_table()?;
+      info.set("shape", style.shape.as_str())?;
+      info.set("blink", style.blink)?;
+      Ok(info)
+    });
+
+    // is_synchronizing()
+    methods.add_method("is_synchronizing", |_, proc, ()| {
+      Ok(proc.lock()?.lock_vt()?.synchronizing())
+    });
+
+    // origin_mode()
+    methods.add_method("origin_mode", |_, proc, ()| {
+      let origin_mode = proc.lock()?.lock_vt()?.origin_mode();
+      Ok(origin_mode)
+    });
+
+    // contents()
+    methods.add_method("contents", |lua, proc, opts: Value| {
+      let opts: Option<ContentsOpts> = lua.from_value(opts)?;
+      let opts = opts.unwrap_or_default();
+      let proc = proc.lock()?;
+      let mut vt = proc.lock_vt()?;
+      let contents = if opts.scrollback {
+        vt.contents_with_scrollback()
+      } else {
+        vt.screen().contents()
+      };
+      Ok(opts.line_ending.apply(contents))
+    });
+
+    // scrollback()
+    methods.add_method("scrollback", |_, proc, ()| {
+      Ok(proc.lock()?.lock_vt()?.scrollback_offset())
+    });
+
+    // set_scrollback(offset)
+    methods.add_method("set_scrollback", |_, proc, offset: usize| {
+      let proc = proc.lock()?;
+      log::info!("{}: set_scrollback(): {}", proc.log_name(), offset);
+      proc.lock_vt()?.set_scrollback(offset);
       Ok(())
     });
 
-    // send_key()
-    methods.add_async_method("send_key", async move |_, proc, key: String| {
-      log::info!("send_key(): {}", key);
-      let key = Key::parse(key.as_str()).map_err(to_lua_err)?;
-      let mut proc = proc.lock()?;
-      proc.send_key(&key);
+    // logical_lines()
+    methods.add_method("logical_lines", |_, proc, ()| {
+      let lines = proc.lock()?.lock_vt()?.logical_lines();
+      Ok(lines)
+    });
+
+    // row_wrapped(y)
+    methods.add_method("row_wrapped", |_, proc, y: u16| {
+      let wrapped = proc.lock()?.lock_vt()?.screen().row_wrapped(y);
+      Ok(wrapped)
+    });
+
+    // contents_hex()
+    methods.add_method("contents_hex", |_, proc, ()| {
+      let contents = proc.lock()?.lock_vt()?.screen().contents();
+      let mut buf = String::new();
+      for ch in contents.chars() {
+        if ch == '\n' || ch == '\r' {
+          buf.push(ch);
+        } else {
+          let num = ch as u32;
+          let s = if num <= 255 {
+            format!(" {:02x}", num)
+          } else {
+            format!(" {:x}", num)
+          };
+          buf.push_str(s.as_str());
+        }
+      }
+      Ok(buf)
+    });
+
+    // serialize()
+    methods.add_method("serialize", |lua, proc, ()| {
+      let blob = proc.lock()?.lock_vt()?.serialize();
+      lua.create_string(&blob)
+    });
+
+    // raw_tail([len])
+    methods.add_method("raw_tail", |lua, proc, len: Option<usize>| {
+      let proc = proc.lock()?;
+      let vt = proc.lock_vt()?;
+      if vt.raw_capacity() == 0 {
+        return Err(mlua::Error::external(
+          "Raw output isn't kept, set `raw_buffer` in vt.start()",
+        ));
+      }
+      let tail = vt.raw_tail(len.unwrap_or(usize::MAX));
+      lua.create_string(&tail)
+    });
+
+    // non_blank_cells()
+    methods.add_method("non_blank_cells", |_, proc, ()| {
+      let count = proc.lock()?.lock_vt()?.non_blank_cells();
+      Ok(count)
+    });
+
+    // mode_transitions()
+    methods.add_method("mode_transitions", |lua, proc, ()| {
+      let proc = proc.lock()?;
+      let vt = proc.lock_vt()?;
+      let list = lua.create_table()?;
+      for (i, transition) in vt.mode_transitions().iter().enumerate() {
+        let item = lua.create_table()?;
+        item.set("mode", transition.mode)?;
+        item.set("value", transition.value.as_str())?;
+        item.set("time_ms", transition.time_ms as u64)?;
+        list.set(i + 1, item)?;
+      }
+      Ok(list)
+    });
+
+    // title()
+    methods.add_method("title", |_, proc, ()| {
+      let title = proc.lock()?.lock_vt()?.screen().title().to_string();
+      Ok(title)
+    });
+
+    // icon_name()
+    methods.add_method("icon_name", |_, proc, ()| {
+      let icon_name = proc.lock()?.lock_vt()?.screen().icon_name().to_string();
+      Ok(icon_name)
+    });
+
+    // title_history()
+    methods.add_method("title_history", |lua, proc, ()| {
+      let proc = proc.lock()?;
+      let vt = proc.lock_vt()?;
+      let list = lua.create_table()?;
+      for (i, change) in vt.title_changes().iter().enumerate() {
+        let item = lua.create_table()?;
+        item.set("title", change.title.as_str())?;
+        item.set("time_ms", change.time_ms as u64)?;
+        list.set(i + 1, item)?;
+      }
+      Ok(list)
+    });
+
+    // bell()
+    methods.add_method("bell", |_, proc, ()| {
+      let rang = proc.lock()?.lock_vt()?.take_bell();
+      Ok(rang)
+    });
+
+    // screen_hash()
+    methods.add_method("screen_hash", |_, proc, ()| {
+      let hash = proc.lock()?.lock_vt()?.screen_hash();
+      // Lua numbers can't hold all 64 bits.
+      Ok(format!("{:016x}", hash))
+    });
+
+    // lint_output()
+    methods.add_method("lint_output", |lua, proc, ()| {
+      let proc = proc.lock()?;
+      let vt = proc.lock_vt()?;
+      let (stray, count) = vt.stray_controls();
+      let report = lua.create_table()?;
+      report.set("count", count)?;
+      let list = lua.create_table()?;
+      for (i, stray) in stray.iter().enumerate() {
+        let item = lua.create_table()?;
+        item.set("pos", stray.pos)?;
+        item.set("byte", stray.code)?;
+        list.set(i + 1, item)?;
+      }
+      report.set("stray", list)?;
+      Ok(report)
+    });
+
+    // replacement_char_count()
+    methods.add_method("replacement_char_count", |_, proc, ()| {
+      let count = proc.lock()?.lock_vt()?.replacement_chars();
+      Ok(count)
+    });
+
+    // send_str
+    methods.add_async_method("send_str", async move |_, proc, str: String| {
+      let written = {
+        let mut proc = proc.lock()?;
+        log::info!("{}: send_str(): {}", proc.log_name(), str);
+        proc
+          .write_input("send_str()", str.as_bytes())
+          .map_err(to_lua_err)?
+      };
+      written.wait().await.map_err(to_lua_err)?;
+      input_delay().await;
       Ok(())
     });
 
+    // paste_bytes(bytes)
+    methods.add_async_method(
+      "paste_bytes",
+      async move |_, proc, bytes: Vec<u8>| {
+        let written = {
+          let mut proc = proc.lock()?;
+          log::info!("{}: paste_bytes(): {:?}", proc.log_name(), bytes);
+          proc.paste(&bytes).map_err(to_lua_err)?
+        };
+        written.wait().await.map_err(to_lua_err)?;
+        input_delay().await;
+        Ok(())
+      },
+    );
+
+    // send_line_wait(line, {prompt, timeout})
+    methods.add_async_method(
+      "send_line_wait",
+      async move |lua, proc, (line, opts): (String, Value)| {
+        let opts: SendLineWaitOpts = lua.from_value(opts)?;
+        let (vt, capture, written) = {
+          let mut proc = proc.lock()?;
+          log::info!(
+            "{}: send_line_wait(): {:?} {:?}",
+            proc.log_name(),
+            line,
+            opts
+          );
+          let capture = proc.lock_vt()?.start_capture();
+          let mut bytes = line.clone().into_bytes();
+          bytes.push(b'\r');
+          let written = proc
+            .write_input("send_line_wait()", &bytes)
+            .map_err(to_lua_err)?;
+          (proc.vt.clone(), capture, written)
+        };
+        if let Err(err) = written.wait().await {
+          vt.lock().unwrap().stop_capture(capture);
+          return Err(to_lua_err(err));
+        }
+        input_delay().await;
+
+        let result = wait_for(&vt, opts.timeout, |vt| {
+          line_output(vt.captured(capture), &line, &opts.prompt)
+        })
+        .await;
+        vt.lock().unwrap().stop_capture(capture);
+        result.map_err(to_lua_err)
+      },
+    );
+
+    // send_key(key, {app_cursor})
+    methods.add_async_method(
+      "send_key",
+      async move |lua, proc, (key, opts): (String, Value)| {
+        let opts: Option<SendKeyOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let written = {
+          let mut proc = proc.lock()?;
+          log::info!("{}: send_key(): {} {:?}", proc.log_name(), key, opts);
+          let key = Key::parse(key.as_str()).map_err(to_lua_err)?;
+          proc.send_key(&key, opts.app_cursor).map_err(to_lua_err)?
+        };
+        written.wait().await.map_err(to_lua_err)?;
+        input_delay().await;
+        Ok(())
+      },
+    );
+
+    // press_until({key, text, max, settle_ms, timeout})
+    methods.add_async_method(
+      "press_until",
+      async move |lua, proc, opts: Value| {
+        let opts: PressUntilOpts = lua.from_value(opts)?;
+        let (vt, key) = {
+          let proc = proc.lock()?;
+          log::info!("{}: press_until(): {:?}", proc.log_name(), opts);
+          let key = Key::parse(opts.key.as_str()).map_err(to_lua_err)?;
+          (proc.vt.clone(), key)
+        };
+        let found = || {
+          let vt = vt.lock().unwrap();
+          vt.screen().contents().contains(opts.text.as_str())
+        };
+        let presses = async {
+          for presses in 0..=opts.max {
+            if presses > 0 {
+              let written =
+                proc.lock()?.send_key(&key, None).map_err(to_lua_err)?;
+              written.wait().await.map_err(to_lua_err)?;
+              input_delay().await;
+              // A screen that keeps changing is checked anyway.
+              let _r = settle(&vt, opts.settle_ms).await;
+            }
+            if found() {
+              return Ok(presses);
+            }
+          }
+          Err(mlua::Error::external(format!(
+            "press_until(): {:?} didn't appear after {} presses of {}",
+            opts.text, opts.max, opts.key
+          )))
+        };
+        match opts.timeout {
+          Some(timeout) => {
+            tokio::time::timeout(Duration::from_millis(timeout), presses)
+              .await
+              .map_err(to_lua_err)?
+          }
+          None => presses.await,
+        }
+      },
+    );
+
+    // run_plan(plan)
+    methods.add_async_method(
+      "run_plan",
+      async move |lua, proc, plan: Vec<mlua::Table>| {
+        let entries = plan
+          .iter()
+          .enumerate()
+          .map(|(i, entry)| PlanEntry::from_table(i + 1, entry))
+          .collect::<mlua::Result<Vec<_>>>()?;
+        let started = {
+          let proc = proc.lock()?;
+          log::info!("{}: run_plan(): {} entries", proc.log_name(), plan.len());
+          proc.started
+        };
+        // Actions are done by calling the methods, so they work exactly like
+        // in scripts.
+        let call: mlua::Function = lua
+          .load("local proc, method = ...\nreturn proc[method](proc, select(3, ...))")
+          .into_function()?;
+        let this = lua.create_userdata(proc.clone())?;
+        let results = lua.create_table()?;
+        for (i, entry) in entries.into_iter().enumerate() {
+          let at = started + Duration::from_millis(entry.at_ms);
+          tokio::time::sleep(at.saturating_duration_since(Instant::now())).await;
+          let time = started.elapsed();
+          call
+            .call_async::<_, mlua::MultiValue>((
+              this.clone(),
+              entry.method,
+              Variadic::from_iter(entry.args),
+            ))
+            .await
+            .map_err(|err| {
+              mlua::Error::external(format!(
+                "run_plan(): Entry {} ({}) failed: {}",
+                i + 1,
+                entry.action,
+                callback_cause(&err)
+              ))
+            })?;
+          let result = lua.create_table()?;
+          result.set("action", entry.action)?;
+          result.set("at_ms", entry.at_ms)?;
+          result.set("time_ms", time.as_millis() as u64)?;
+          let duration = started.elapsed().saturating_sub(time);
+          result.set("duration_ms", duration.as_millis() as u64)?;
+          results.set(i + 1, result)?;
+        }
+        Ok(results)
+      },
+    );
+
+    // flush()
+    methods.add_async_method("flush", async move |_, proc, ()| {
+      let written = {
+        let mut proc = proc.lock()?;
+        log::info!("{}: flush()", proc.log_name());
+        proc.flush().map_err(to_lua_err)?
+      };
+      written.wait().await.map_err(to_lua_err)
+    });
+
+    // close_input()
+    methods.add_async_method("close_input", async move |_, proc, ()| {
+      let written = {
+        let mut proc = proc.lock()?;
+        log::info!("{}: close_input()", proc.log_name());
+        proc.close_input().map_err(to_lua_err)?
+      };
+      written.wait().await.map_err(to_lua_err)
+    });
+
     // click()
-    methods.add_method("click", |lua, proc, opts: Value| {
+    methods.add_async_method("click", async move |lua, proc, opts: Value| {
       let opts: ClickParams = lua.from_value(opts).map_err(to_lua_err)?;
       let btn = match opts.button {
         ClickButton::Left => MouseButton::Left,
         ClickButton::Right => MouseButton::Right,
         ClickButton::Middle => MouseButton::Middle,
       };
+      let modifiers = match &opts.modifiers {
+        Some(modifiers) => modifiers.parse().map_err(to_lua_err)?,
+        None => KeyModifiers::NONE,
+      };
       let action = MouseAction(MouseEvent {
         kind: MouseEventKind::Down(btn),
         row: opts.y,
         column: opts.x,
-        modifiers: KeyModifiers::NONE,
+        modifiers,
       });
-      proc.lock()?.send_mouse(&action).map_err(to_lua_err)?;
-      Ok(())
+      let written = proc.lock()?.send_mouse(&action).map_err(to_lua_err)?;
+      written.wait().await.map_err(to_lua_err)
     });
 
+    // mouse_move()
+    methods.add_async_method(
+      "mouse_move",
+      async move |lua, proc, opts: Value| {
+        let opts: MouseMoveParams = lua.from_value(opts).map_err(to_lua_err)?;
+        let kind = match opts.button {
+          None => MouseEventKind::Moved,
+          Some(ClickButton::Left) => MouseEventKind::Drag(MouseButton::Left),
+          Some(ClickButton::Right) => MouseEventKind::Drag(MouseButton::Right),
+          Some(ClickButton::Middle) => {
+            MouseEventKind::Drag(MouseButton::Middle)
+          }
+        };
+        let action = MouseAction(MouseEvent {
+          kind,
+          row: opts.y,
+          column: opts.x,
+          modifiers: KeyModifiers::NONE,
+        });
+        let written = proc.lock()?.send_mouse(&action).map_err(to_lua_err)?;
+        written.wait().await.map_err(to_lua_err)
+      },
+    );
+
     // scroll()
-    methods.add_method("scroll", |lua, proc, opts: Value| {
+    methods.add_async_method("scroll", async move |lua, proc, opts: Value| {
       let opts: ScrollParams = lua.from_value(opts).map_err(to_lua_err)?;
       let kind = match opts.dir {
         ScrollDir::Up => MouseEventKind::ScrollUp,
@@ -381,8 +1749,8 @@ impl UserData for LuaProc {
         column: opts.x,
         modifiers: KeyModifiers::NONE,
       });
-      proc.lock()?.send_mouse(&action).map_err(to_lua_err)?;
-      Ok(())
+      let written = proc.lock()?.send_mouse(&action).map_err(to_lua_err)?;
+      written.wait().await.map_err(to_lua_err)
     });
 
     // send_signal
@@ -400,81 +1768,649 @@ impl UserData for LuaProc {
           ))
         }
       };
-      log::info!("send_signal(): {:?}", str);
-      proc.lock()?.send_signal(sig);
+      let mut proc = proc.lock()?;
+      log::info!("{}: send_signal(): {:?}", proc.log_name(), str);
+      proc.send_signal(sig).map_err(to_lua_err)?;
       Ok(())
     });
 
     // kill()
     methods.add_method("kill", |_, proc, ()| {
-      log::info!("kill()");
-      proc.lock()?.killer.kill().map_err(to_lua_err)
+      let mut proc = proc.lock()?;
+      log::info!("{}: kill()", proc.log_name());
+      proc
+        .child_mut()
+        .map_err(to_lua_err)?
+        .killer
+        .kill()
+        .map_err(to_lua_err)
     });
 
     // resize
     methods.add_async_method("resize", async move |lua, proc, opts: Value| {
       let opts: ResizeConfig = lua.from_value(opts).map_err(to_lua_err)?;
-      proc.lock()?.resize(opts).await.map_err(to_lua_err)
+      let mut proc = proc.lock()?;
+      log::info!("{}: resize(): {:?}", proc.log_name(), opts);
+      if !proc.resize(opts).map_err(to_lua_err)? {
+        log::info!("{}: resize(): Same size, skipped", proc.log_name());
+      }
+      Ok(())
+    });
+
+    // is_alive()
+    methods.add_method("is_alive", |_, proc, ()| {
+      let alive = proc.lock()?.exit_status().map_err(to_lua_err)?.is_none();
+      Ok(alive)
+    });
+
+    // exit_code()
+    methods.add_method("exit_code", |_, proc, ()| {
+      let code = match proc.lock()?.exit_status().map_err(to_lua_err)? {
+        Some(Ok(status)) => Some(status.exit_code()),
+        Some(Err(_)) | None => None,
+      };
+      Ok(code)
     });
 
     // wait()
     methods.add_async_method("wait", async move |_, proc, ()| {
-      log::info!("wait()");
-      proc.lock()?.wait().await.map_err(to_lua_err)
+      let (name, exit) = {
+        let proc = proc.lock()?;
+        log::info!("{}: wait()", proc.log_name());
+        let exit = proc.child().map_err(to_lua_err)?.exit.clone();
+        (proc.log_name(), exit)
+      };
+      // Failing to wait isn't a script error, it's logged by `Proc::wait()`.
+      let status = Proc::wait(&name, exit).await.ok();
+      Ok(status.map(|status| status.exit_code()))
     });
 
-    // wait_text(text, {timeout})
+    // wait_exit({timeout})
+    methods.add_async_method(
+      "wait_exit",
+      async move |lua, proc, opts: Value| {
+        let opts: Option<WaitOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let (name, exit) = {
+          let proc = proc.lock()?;
+          log::info!("{}: wait_exit(): {:?}", proc.log_name(), opts);
+          let exit = proc.child().map_err(to_lua_err)?.exit.clone();
+          (proc.log_name(), exit)
+        };
+        let timeout =
+          Duration::from_millis(opts.timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT));
+        match tokio::time::timeout(timeout, Proc::wait(&name, exit)).await {
+          Ok(status) => {
+            Ok((true, status.ok().map(|status| status.exit_code())))
+          }
+          Err(_) => {
+            log::info!("{}: wait_exit(): Still running", name);
+            Ok((false, None))
+          }
+        }
+      },
+    );
+
+    // wait_eof({timeout})
+    methods.add_async_method(
+      "wait_eof",
+      async move |lua, proc, opts: Value| {
+        let opts: Option<WaitOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let eof = {
+          let proc = proc.lock()?;
+          log::info!("{}: wait_eof(): {:?}", proc.log_name(), opts);
+          proc.child().map_err(to_lua_err)?.eof.clone()
+        };
+        Proc::wait_eof(eof, opts.timeout).await.map_err(to_lua_err)
+      },
+    );
+
+    // wait_text(text, {timeout, count, source})
     methods.add_async_method(
       "wait_text",
-      async move |_, proc, (text, opts): (String, Option<mlua::Table>)| {
-        log::info!("wait_text(): {:?} {:?}", text, opts);
-        let timeout = opts
-          .map(|opts| opts.get("timeout"))
-          .transpose()?
-          .unwrap_or(1500);
-
-        let proc = &proc.lock()?;
-        let timeout = Duration::from_millis(timeout);
-        tokio::time::timeout(timeout, async {
-          loop {
-            if proc
-              .lock_vt()
-              .unwrap()
-              .screen()
-              .contents()
-              .contains(text.as_str())
-            {
-              break ();
+      async move |lua, proc, (text, opts): (String, Value)| {
+        let opts: Option<WaitTextOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let vt = {
+          let proc = proc.lock()?;
+          log::info!("{}: wait_text(): {:?} {:?}", proc.log_name(), text, opts);
+          proc.vt.clone()
+        };
+        let count = opts.count.unwrap_or(1);
+        let folds = opts.ignore_case || opts.collapse_whitespace;
+        if folds && !matches!(opts.source, TextSource::Screen) {
+          return Err(mlua::Error::external(
+            "wait_text(): ignore_case and collapse_whitespace only work with \
+             the screen source",
+          ));
+        }
+        if opts.regex && !matches!(opts.source, TextSource::Screen) {
+          return Err(mlua::Error::external(
+            "wait_text(): regex only works with the screen source",
+          ));
+        }
+        let regex = if opts.regex {
+          let regex = regex::RegexBuilder::new(&text)
+            .case_insensitive(opts.ignore_case)
+            .build()
+            .map_err(|err| {
+              mlua::Error::external(format!(
+                "wait_text(): Invalid regex: {}",
+                err
+              ))
+            })?;
+          Some(regex)
+        } else {
+          None
+        };
+        if matches!(opts.source, TextSource::Raw)
+          && vt.lock().unwrap().raw_capacity() == 0
+        {
+          return Err(mlua::Error::external(
+            "wait_text(): Raw output isn't kept, set `raw_buffer` in vt.start()",
+          ));
+        }
+        let counter = match opts.source {
+          TextSource::Stream => Some(vt.lock().unwrap().add_counter(&text)),
+          TextSource::Screen | TextSource::Raw => None,
+        };
+        let needle = opts.fold(&text);
+
+        let result = wait_for(&vt, opts.timeout, |vt| {
+          let found = match (&opts.source, counter) {
+            (_, Some(id)) => vt.counter(id),
+            (TextSource::Raw, None) => vt.count_raw(&text),
+            (_, None) => {
+              let contents = opts.fold(&vt.screen().contents());
+              match &regex {
+                Some(regex) => regex.find_iter(&contents).count(),
+                None => contents.matches(needle.as_str()).count(),
+              }
             }
-            tokio::time::sleep(Duration::from_millis(200)).await;
-          }
+          };
+          (found >= count).then_some(())
+        })
+        .await;
+        if let Some(id) = counter {
+          vt.lock().unwrap().remove_counter(id);
+        }
+        result.map_err(to_lua_err)?;
+        Ok(())
+      },
+    );
+
+    // wait_cursor_visible({timeout})
+    methods.add_async_method(
+      "wait_cursor_visible",
+      async move |lua, proc, opts: Value| {
+        let opts: Option<WaitOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let vt = {
+          let proc = proc.lock()?;
+          log::info!("{}: wait_cursor_visible(): {:?}", proc.log_name(), opts);
+          proc.vt.clone()
+        };
+        wait_for(&vt, opts.timeout, |vt| {
+          (!vt.screen().hide_cursor()).then_some(())
+        })
+        .await
+        .map_err(to_lua_err)
+      },
+    );
+
+    // wait_cursor_hidden({timeout})
+    methods.add_async_method(
+      "wait_cursor_hidden",
+      async move |lua, proc, opts: Value| {
+        let opts: Option<WaitOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let vt = {
+          let proc = proc.lock()?;
+          log::info!("{}: wait_cursor_hidden(): {:?}", proc.log_name(), opts);
+          proc.vt.clone()
+        };
+        wait_for(&vt, opts.timeout, |vt| {
+          vt.screen().hide_cursor().then_some(())
+        })
+        .await
+        .map_err(to_lua_err)
+      },
+    );
+
+    // wait_cursor(x, y, {timeout})
+    methods.add_async_method(
+      "wait_cursor",
+      async move |lua, proc, (x, y, opts): (u16, u16, Value)| {
+        let opts: Option<WaitOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let vt = {
+          let proc = proc.lock()?;
+          log::info!(
+            "{}: wait_cursor(): x={} y={} {:?}",
+            proc.log_name(),
+            x,
+            y,
+            opts
+          );
+          proc.vt.clone()
+        };
+        wait_for(&vt, opts.timeout, |vt| {
+          (vt.screen().cursor_position() == (y, x)).then_some(())
+        })
+        .await
+        .map_err(to_lua_err)
+      },
+    );
+
+    // wait_sync({timeout})
+    methods.add_async_method(
+      "wait_sync",
+      async move |lua, proc, opts: Value| {
+        let opts: Option<WaitOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let vt = {
+          let proc = proc.lock()?;
+          log::info!("{}: wait_sync(): {:?}", proc.log_name(), opts);
+          proc.vt.clone()
+        };
+        wait_for(&vt, opts.timeout, |vt| (!vt.synchronizing()).then_some(()))
+          .await
+          .map_err(to_lua_err)
+      },
+    );
+
+    // wait_row(y, pattern, {timeout})
+    methods.add_async_method(
+      "wait_row",
+      async move |lua, proc, (y, pattern, opts): (u16, String, Value)| {
+        let opts: Option<WaitOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let vt = {
+          let proc = proc.lock()?;
+          log::info!(
+            "{}: wait_row(): {} {:?} {:?}",
+            proc.log_name(),
+            y,
+            pattern,
+            opts
+          );
+          proc.vt.clone()
+        };
+        let regex = regex::Regex::new(&pattern).map_err(to_lua_err)?;
+        let rows = vt.lock().unwrap().screen().size().0;
+        if y >= rows {
+          return Err(mlua::Error::external(format!(
+            "wait_row(): Row {} is out of the screen ({} rows)",
+            y, rows
+          )));
+        }
+
+        let captures = wait_for(&vt, opts.timeout, |vt| {
+          let cols = vt.screen().size().1;
+          let row = vt.screen().rows(0, cols).nth(y as usize)?;
+          let captures = regex.captures(&row)?;
+          Some(row_captures(&captures))
         })
         .await
         .map_err(to_lua_err)?;
+        Ok(Variadic::from_iter(captures))
+      },
+    );
+
+    // dump_txt(path, {unprintable})
+    methods.add_async_method(
+      "dump_txt",
+      async move |lua, proc, (path, opts): (String, Value)| {
+        check_sandbox("dump_txt")?;
+        let opts: Option<DumpTxtOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let proc = proc.lock()?;
+        log::info!("{}: dump_txt()", proc.log_name());
+        let vt = proc.lock_vt()?;
+        dump_txt(vt.screen(), &path, opts.unprintable, opts.line_ending)
+          .map_err(to_lua_err)?;
         Ok(())
       },
     );
 
-    // dump_txt(path)
-    methods.add_async_method("dump_txt", async move |_, proc, path: String| {
-      log::info!("dump_txt()");
-      let proc = proc.lock()?;
-      let vt = proc.lock_vt()?;
-      dump_txt(vt.screen(), path.as_str()).map_err(to_lua_err)?;
-      Ok(())
-    });
+    // export_html(path)
+    methods.add_async_method(
+      "export_html",
+      async move |_, proc, path: String| {
+        check_sandbox("export_html")?;
+        let proc = proc.lock()?;
+        log::info!("{}: export_html(): {}", proc.log_name(), path);
+        let vt = proc.lock_vt()?;
+        dump_html(vt.screen(), vt.palette(), &path).map_err(to_lua_err)?;
+        Ok(())
+      },
+    );
 
-    // dump_png(path)
-    methods.add_async_method("dump_png", async move |_, proc, path: String| {
-      log::info!("dump_png()");
-      let proc = proc.lock()?;
-      let vt = proc.lock_vt()?;
-      dump_png(vt.screen(), path.as_str()).map_err(to_lua_err)?;
-      Ok(())
-    });
+    // export_ansi(path)
+    methods.add_async_method(
+      "export_ansi",
+      async move |_, proc, path: String| {
+        check_sandbox("export_ansi")?;
+        let proc = proc.lock()?;
+        log::info!("{}: export_ansi(): {}", proc.log_name(), path);
+        let vt = proc.lock_vt()?;
+        dump_ansi(vt.screen(), &path).map_err(to_lua_err)?;
+        Ok(())
+      },
+    );
+
+    // dump(basename, {json})
+    methods.add_async_method(
+      "dump",
+      async move |lua, proc, (basename, opts): (String, Value)| {
+        check_sandbox("dump")?;
+        let opts: Option<DumpOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let proc = proc.lock()?;
+        log::info!("{}: dump(): {} {:?}", proc.log_name(), basename, opts);
+        let vt = proc.lock_vt()?;
+        let screen = vt.screen();
+
+        let mut paths = Vec::new();
+        let path = format!("{}.txt", basename);
+        dump_txt(screen, &path, None, opts.line_ending).map_err(to_lua_err)?;
+        paths.push(path);
+        let path = format!("{}.png", basename);
+        dump_png(screen, vt.palette(), &path).map_err(to_lua_err)?;
+        paths.push(path);
+        if opts.json {
+          let path = format!("{}.json", basename);
+          dump_json(screen, &path).map_err(to_lua_err)?;
+          paths.push(path);
+        }
+        Ok(paths)
+      },
+    );
+
+    // dump_png(path, {include_scrollback, max_scrollback, unprintable,
+    //   caption, scale, cell_width, cell_height, font, theme})
+    methods.add_async_method(
+      "dump_png",
+      async move |lua, proc, (path, opts): (String, Value)| {
+        check_sandbox("dump_png")?;
+        let opts: Option<DumpPngOpts> = lua.from_value(opts)?;
+        let opts = opts.unwrap_or_default();
+        let proc = proc.lock()?;
+        log::info!("{}: dump_png(): {:?}", proc.log_name(), opts);
+        let mut vt = proc.lock_vt()?;
+        let mut renderer = Renderer::new().map_err(to_lua_err)?;
+        renderer.set_unprintable(opts.unprintable);
+        renderer.set_palette(vt.palette());
+        if let Some(theme) = &opts.theme {
+          renderer.set_theme(theme).map_err(to_lua_err)?;
+        }
+        if let Some(font) = &opts.font {
+          renderer.set_fonts(font).map_err(to_lua_err)?;
+        }
+        if let Some(scale) = opts.scale {
+          renderer.set_font_px(scale).map_err(to_lua_err)?;
+        }
+        renderer
+          .set_cell_size(opts.cell_width, opts.cell_height)
+          .map_err(to_lua_err)?;
+        let canvas = if opts.include_scrollback {
+          let max_scrollback = opts.max_scrollback.unwrap_or(usize::MAX);
+          let rows = vt.rows_with_scrollback(max_scrollback);
+          renderer.render_rows(&rows)
+        } else {
+          renderer.render(vt.screen())
+        };
+        let canvas = match &opts.caption {
+          Some(caption) => renderer.add_label(&canvas, caption),
+          None => canvas,
+        };
+        dump_png::save(&canvas, path.as_str()).map_err(to_lua_err)?;
+        Ok(())
+      },
+    );
+
+    // dump_diff_png(baseline, path)
+    methods.add_async_method(
+      "dump_diff_png",
+      async move |_, proc, (baseline, path): (mlua::String, String)| {
+        check_sandbox("dump_diff_png")?;
+        let proc = proc.lock()?;
+        log::info!("{}: dump_diff_png(): {}", proc.log_name(), path);
+        let baseline =
+          Vt::deserialize(baseline.as_bytes()).map_err(to_lua_err)?;
+        let vt = proc.lock_vt()?;
+        let mut renderer = Renderer::new().map_err(to_lua_err)?;
+        renderer.set_palette(vt.palette());
+        let canvas = renderer.render_diff(vt.screen(), baseline.screen());
+        dump_png::save(&canvas, path.as_str()).map_err(to_lua_err)?;
+        Ok(())
+      },
+    );
+  }
+}
+
+/// Runs the command with `nice`, so the niceness is set before the exec and
+/// is inherited by the children. portable_pty has no pre-exec hook. `nice`
+/// takes an increment to its own niceness, which is the niceness of virterm.
+#[cfg(not(windows))]
+fn set_nice(cmd: &mut portable_pty::CommandBuilder, nice: i32) {
+  let current = unsafe { libc::getpriority(libc::PRIO_PROCESS as _, 0) };
+  let increment = (nice - current).to_string();
+  cmd
+    .get_argv_mut()
+    .splice(0..0, ["nice".into(), "-n".into(), increment.into()]);
+}
+
+#[cfg(windows)]
+fn set_nice(_cmd: &mut portable_pty::CommandBuilder, nice: i32) {
+  log::warn!("Ignoring nice {}, it isn't supported on Windows", nice);
+}
+
+/// portable_pty doesn't expose the slave path, so it's read from the stdin of
+/// the child that was just spawned on the slave.
+#[cfg(target_os = "linux")]
+fn tty_name(pid: i32) -> Option<String> {
+  let path = std::fs::read_link(format!("/proc/{}/fd/0", pid)).ok()?;
+  let path = path.to_str()?;
+  path.starts_with("/dev/").then(|| path.to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tty_name(_pid: i32) -> Option<String> {
+  None
+}
+
+/// Bytes to write to the pty and where to report the result of the write.
+type Input = (Vec<u8>, oneshot::Sender<std::io::Result<()>>);
+
+/// Resolves when the input queued by `Proc::write_input()` is written.
+pub struct InputWritten(Option<oneshot::Receiver<std::io::Result<()>>>);
+
+impl InputWritten {
+  pub async fn wait(self) -> Result<()> {
+    if let Some(written) = self.0 {
+      written
+        .await
+        .map_err(|_| anyhow!("Input writer stopped"))??;
+    }
+    Ok(())
+  }
+}
+
+/// Feeds the child output into the terminal and writes the terminal replies
+/// back to the child.
+fn process_output(
+  vt: &Mutex<Vt>,
+  bytes: &[u8],
+  replies_writer: &mut impl Write,
+  log_name: &str,
+) {
+  let (transitions, replies) = {
+    let mut vt = vt.lock().unwrap();
+    (vt.process(bytes), vt.take_replies())
+  };
+  if !replies.is_empty() {
+    log::debug!("{}: Reply: {}", log_name, replies.escape_ascii());
+    if let Err(err) = replies_writer.write_all(&replies) {
+      log::warn!("{}: Failed to reply: {}", log_name, err);
+    }
+  }
+  for transition in transitions {
+    log::debug!(
+      "{}: Mode {} = {} at +{}ms",
+      log_name,
+      transition.mode,
+      transition.value,
+      transition.time_ms
+    );
+  }
+}
+
+/// Default timeout of the `wait_*()` methods in milliseconds.
+const DEFAULT_WAIT_TIMEOUT: u64 = 1500;
+
+/// Number of changed cells listed by `assert_region_unchanged()`.
+const REGION_CHANGES_LIMIT: usize = 10;
+
+/// Interval of the checks of `wait_for()` while there is no output, for the
+/// checks that depend on time or on changes that aren't output, e.g. resizes.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Checks the terminal after every output and at least every
+/// `WAIT_POLL_INTERVAL` until `check` returns a value. Fails after `timeout`
+/// milliseconds. With `respect_sync` set in the terminal, it isn't checked in
+/// the middle of a synchronized frame.
+async fn wait_for<T>(
+  vt: &Mutex<Vt>,
+  timeout: Option<u64>,
+  mut check: impl FnMut(&mut Vt) -> Option<T>,
+) -> Result<T> {
+  let timeout = Duration::from_millis(timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT));
+  let mut output = vt.lock().unwrap().output_changes();
+  let result = tokio::time::timeout(timeout, async {
+    loop {
+      // Marked as seen before the check, so output processed while checking
+      // wakes the next wait.
+      output.borrow_and_update();
+      {
+        let mut vt = vt.lock().unwrap();
+        let torn = vt.respect_sync() && vt.synchronizing();
+        if let Some(value) = (!torn).then(|| check(&mut vt)).flatten() {
+          break value;
+        }
+      }
+      let _ = tokio::time::timeout(WAIT_POLL_INTERVAL, output.changed()).await;
+    }
+  })
+  .await?;
+  Ok(result)
+}
+
+/// Bytes of a paste. Like in xterm, the content isn't filtered, so a paste end
+/// sequence inside of it ends the paste early.
+fn paste_frame(bytes: &[u8], bracketed: bool) -> Vec<u8> {
+  if !bracketed {
+    return bytes.to_vec();
+  }
+  [b"\x1b[200~", bytes, b"\x1b[201~"].concat()
+}
+
+/// Like `string.match()` in Lua: the captured groups (`None` for the groups
+/// that didn't participate), or the whole match if there are no groups.
+fn row_captures(captures: &regex::Captures) -> Vec<Option<String>> {
+  let text = |m: Option<regex::Match>| m.map(|m| m.as_str().to_string());
+  if captures.len() == 1 {
+    vec![text(captures.get(0))]
+  } else {
+    captures.iter().skip(1).map(text).collect()
   }
 }
 
+/// Waits until the screen doesn't change for `settle_ms`. Gives up after
+/// `settle_ms + DEFAULT_WAIT_TIMEOUT`, e.g. when a spinner never stops.
+pub async fn settle(vt: &Mutex<Vt>, settle_ms: u64) -> Result<()> {
+  let settle = Duration::from_millis(settle_ms);
+  let mut last: Option<(u64, Instant)> = None;
+  wait_for(vt, Some(settle_ms + DEFAULT_WAIT_TIMEOUT), |vt| {
+    let hash = vt.screen_hash();
+    match last {
+      Some((last_hash, since)) if last_hash == hash => {
+        (since.elapsed() >= settle).then_some(())
+      }
+      _ => {
+        last = Some((hash, Instant::now()));
+        None
+      }
+    }
+  })
+  .await
+}
+
+/// Output of a line sent by `send_line_wait()`: the text printed after the
+/// echo of the line and before the prompt. `None` until the prompt is printed.
+fn line_output(captured: &[u8], line: &str, prompt: &str) -> Option<String> {
+  let text =
+    String::from_utf8_lossy(&strip_escapes(captured)).replace('\r', "");
+  let text = match text.strip_prefix(line) {
+    Some(rest) => rest.strip_prefix('\n').unwrap_or(rest),
+    None => text.as_str(),
+  };
+  let end = text.rfind(prompt)?;
+  Some(text[..end].to_string())
+}
+
+/// Pids of the direct children of the process.
+#[cfg(target_os = "linux")]
+pub fn child_pids(pid: i32) -> Vec<i32> {
+  let mut pids = Vec::new();
+  let tasks = match std::fs::read_dir(format!("/proc/{}/task", pid)) {
+    Ok(tasks) => tasks,
+    Err(_) => return pids,
+  };
+  for task in tasks.flatten() {
+    match std::fs::read_to_string(task.path().join("children")) {
+      Ok(children) => pids.extend(
+        children
+          .split_whitespace()
+          .filter_map(|s| s.parse::<i32>().ok()),
+      ),
+      // The kernel may be built without `children` files.
+      Err(_) => return child_pids_by_ppid(pid),
+    }
+  }
+  pids.sort_unstable();
+  pids.dedup();
+  pids
+}
+
+/// Finds children by scanning the parent pids of all processes.
+#[cfg(target_os = "linux")]
+fn child_pids_by_ppid(pid: i32) -> Vec<i32> {
+  let entries = match std::fs::read_dir("/proc") {
+    Ok(entries) => entries,
+    Err(_) => return Vec::new(),
+  };
+  let mut pids: Vec<i32> = entries
+    .flatten()
+    .filter_map(|entry| {
+      let child: i32 = entry.file_name().to_str()?.parse().ok()?;
+      let stat = std::fs::read_to_string(entry.path().join("stat")).ok()?;
+      // "pid (comm) state ppid ...", comm may contain anything.
+      let rest = &stat[stat.rfind(')')? + 1..];
+      let ppid: i32 = rest.split_whitespace().nth(1)?.parse().ok()?;
+      (ppid == pid).then_some(child)
+    })
+    .collect();
+  pids.sort_unstable();
+  pids
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn child_pids(_pid: i32) -> Vec<i32> {
+  Vec::new()
+}
+
 fn signal_from_string(sig: &str) -> Result<libc::c_int> {
   let sig = match sig {
     "SIGHUP" => 1,
@@ -494,6 +2430,42 @@ fn signal_from_string(sig: &str) -> Result<libc::c_int> {
   Ok(sig)
 }
 
+/// Sets the style of `cell` in the tables returned by `cell()` and
+/// `line_runs()`.
+fn set_cell_style(
+  lua: &Lua,
+  table: &mlua::Table,
+  cell: &vt100::Cell,
+) -> mlua::Result<()> {
+  table.set("fg", from_vt_color(lua, cell.fgcolor())?)?;
+  table.set("bg", from_vt_color(lua, cell.bgcolor())?)?;
+  table.set("bold", cell.bold())?;
+  table.set("italic", cell.italic())?;
+  table.set("underline", cell.underline())?;
+  table.set("inverse", cell.inverse())?;
+  Ok(())
+}
+
+fn same_style(a: &vt100::Cell, b: &vt100::Cell) -> bool {
+  a.fgcolor() == b.fgcolor()
+    && a.bgcolor() == b.bgcolor()
+    && a.bold() == b.bold()
+    && a.italic() == b.italic()
+    && a.underline() == b.underline()
+    && a.inverse() == b.inverse()
+}
+
+fn line_run(
+  lua: &Lua,
+  (x, cell, text): (u16, vt100::Cell, String),
+) -> mlua::Result<mlua::Table<'_>> {
+  let run = lua.create_table()?;
+  run.set("x", x)?;
+  run.set("text", text)?;
+  set_cell_style(lua, &run, &cell)?;
+  Ok(run)
+}
+
 fn from_vt_color<'lua>(
   lua: &'lua Lua,
   color: vt100::Color,
@@ -508,3 +2480,502 @@ fn from_vt_color<'lua>(
   };
   Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// `kill_all()` kills processes of the other tests, run them one by one.
+  static PROCS: Mutex<()> = Mutex::new(());
+
+  /// Runs `test` on a fresh runtime, one test at a time. Dropping the runtime
+  /// waits for the pty readers, they stop once the processes are gone.
+  fn with_runtime<F: std::future::Future>(test: F) -> F::Output {
+    let _procs = PROCS.lock().unwrap_or_else(|e| e.into_inner());
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(test)
+  }
+
+  #[test]
+  fn kill_all_running() {
+    with_runtime(async {
+      let proc = Proc::shell("sleep 100", &ProcConfig::default()).unwrap();
+      let wait = Proc::wait("test", proc.child().unwrap().exit.clone());
+
+      kill_all();
+
+      let status = tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .expect("process wasn't killed")
+        .unwrap();
+      assert!(!status.success());
+      assert!(!RUNNING.lock().unwrap().iter().any(|(id, _)| *id == proc.id));
+    });
+  }
+
+  #[test]
+  fn close_input() {
+    with_runtime(async {
+      let mut proc = Proc::shell("cat", &ProcConfig::default()).unwrap();
+      let wait = Proc::wait("test", proc.child().unwrap().exit.clone());
+
+      // A pending line needs a second Ctrl-D.
+      proc.write_input("test", b"hello").unwrap();
+      proc.close_input().unwrap();
+
+      let status = tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .expect("cat didn't get EOF")
+        .unwrap();
+      assert!(status.success());
+    });
+  }
+
+  #[test]
+  fn write_input_full_buffer() {
+    with_runtime(async {
+      let cmd = "stty raw -echo; sleep 10";
+      let mut proc = Proc::shell(cmd, &ProcConfig::default()).unwrap();
+      tokio::time::sleep(Duration::from_millis(300)).await;
+
+      // The process doesn't read, so the write can't complete, but queuing it
+      // doesn't block.
+      let written = proc.write_input("test", &[b'a'; 1 << 20]).unwrap();
+      let wait =
+        tokio::time::timeout(Duration::from_millis(300), written.wait());
+      assert!(wait.await.is_err());
+
+      proc.child_mut().unwrap().killer.kill().unwrap();
+    });
+  }
+
+  #[test]
+  fn drop_stops_reader() {
+    with_runtime(async {
+      let proc = Proc::shell("sleep 100", &ProcConfig::default()).unwrap();
+      let vt = proc.vt.clone();
+      let exit = proc.child().unwrap().exit.clone();
+      drop(proc);
+
+      let deadline = Instant::now() + Duration::from_secs(5);
+      while Arc::strong_count(&vt) > 1 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+      // The reader thread released its reference to the terminal.
+      assert_eq!(Arc::strong_count(&vt), 1);
+      // The process is hung up.
+      let exited =
+        tokio::time::timeout(Duration::from_secs(5), Proc::wait("test", exit));
+      assert!(exited.await.is_ok());
+    });
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn drop_stops_reader_of_background_job() {
+    with_runtime(async {
+      let proc = Proc::shell("sleep 100 &", &ProcConfig::default()).unwrap();
+      let vt = proc.vt.clone();
+      let exit = proc.child().unwrap().exit.clone();
+      // The shell exits right away, the kernel hangs up the job holding the
+      // pty slave.
+      let exited =
+        tokio::time::timeout(Duration::from_secs(5), Proc::wait("test", exit));
+      assert!(exited.await.is_ok());
+      drop(proc);
+
+      let deadline = Instant::now() + Duration::from_secs(5);
+      while Arc::strong_count(&vt) > 1 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+      assert_eq!(Arc::strong_count(&vt), 1);
+    });
+  }
+
+  #[test]
+  fn wait_eof() {
+    with_runtime(async {
+      let proc = Proc::shell("true", &ProcConfig::default()).unwrap();
+      let eof = proc.child().unwrap().eof.clone();
+      assert!(Proc::wait_eof(eof, Some(5000)).await.is_ok());
+
+      // The output ends before the process exits.
+      let cmd = "exec </dev/null >/dev/null 2>&1; sleep 1";
+      let proc = Proc::shell(cmd, &ProcConfig::default()).unwrap();
+      let eof = proc.child().unwrap().eof.clone();
+      assert!(Proc::wait_eof(eof, Some(5000)).await.is_ok());
+      assert!(proc.exit_status().unwrap().is_none());
+    });
+  }
+
+  #[test]
+  fn reader_stress() {
+    with_runtime(async {
+      let mut proc = Proc::shell("yes", &ProcConfig::default()).unwrap();
+      tokio::time::sleep(Duration::from_millis(300)).await;
+      assert!(proc
+        .vt
+        .lock()
+        .unwrap()
+        .screen()
+        .contents()
+        .starts_with("y\n"));
+      // The reader keeps up with the output and sees its end once the process
+      // is killed.
+      proc.child_mut().unwrap().killer.kill().unwrap();
+      let eof = proc.child().unwrap().eof.clone();
+      assert!(Proc::wait_eof(eof, Some(5000)).await.is_ok());
+    });
+  }
+
+  #[test]
+  fn scrollback() {
+    with_runtime(async {
+      for (scrollback, expected) in [(0, "4\n5"), (2, "2\n3\n4\n5")] {
+        let cfg = ProcConfig {
+          height: 3,
+          scrollback,
+          ..Default::default()
+        };
+        let proc = Proc::shell("seq 1 5", &cfg).unwrap();
+        let name = proc.log_name();
+        let exit = proc.child().unwrap().exit.clone();
+        Proc::wait(&name, exit).await.unwrap();
+        let eof = proc.child().unwrap().eof.clone();
+        Proc::wait_eof(eof, Some(5000)).await.unwrap();
+        let mut vt = proc.vt.lock().unwrap();
+        assert_eq!(vt.contents_with_scrollback(), expected);
+      }
+    });
+  }
+
+  #[test]
+  fn plan_entries() {
+    let lua = Lua::new();
+    let entry = |src: &str| {
+      let table: mlua::Table = lua.load(src).eval().unwrap();
+      PlanEntry::from_table(2, &table)
+        .map(|entry| (entry.at_ms, entry.method, entry.args.len()))
+    };
+    let err = |src: &str| entry(src).err().unwrap().to_string();
+    assert_eq!(
+      entry("{ at_ms = 10, action = 'screenshot', path = 'a.png' }").unwrap(),
+      (10, "dump_png", 2)
+    );
+    assert_eq!(
+      err("{ at_ms = 10, action = 'click' }"),
+      "run_plan(): Entry 2: Unknown action: \"click\""
+    );
+    assert_eq!(
+      err("{ at_ms = 10, action = 'send_key', opts = {} }"),
+      "run_plan(): Entry 2: send_key needs key"
+    );
+    assert_eq!(
+      err("{ action = 'send_str', text = 'a' }"),
+      "run_plan(): Entry 2: at_ms must be a non-negative number"
+    );
+  }
+
+  #[test]
+  fn screen_without_process() {
+    let mut proc = Proc::screen(&ScreenConfig {
+      width: 10,
+      height: 2,
+      scrollback: 0,
+      track_cells: false,
+    })
+    .unwrap();
+    proc.parse_output(b"hello\x1b[6n");
+    assert_eq!(proc.lock_vt().unwrap().screen().contents(), "hello");
+    let err = proc.write_input("test", b"a").err().unwrap();
+    assert!(err.to_string().ends_with("The screen has no process"));
+    assert!(proc
+      .resize(ResizeConfig {
+        width: 20,
+        height: 2
+      })
+      .unwrap());
+  }
+
+  #[test]
+  fn wait_text_fold() {
+    let opts = WaitTextOpts {
+      ignore_case: true,
+      collapse_whitespace: true,
+      ..Default::default()
+    };
+    assert_eq!(opts.fold("Build  \t Succeeded\n"), "build succeeded ");
+    assert_eq!(WaitTextOpts::default().fold("A  b"), "A  b");
+  }
+
+  #[test]
+  fn locale_override() {
+    assert!(!sets_locale(None));
+    let mut env = HashMap::new();
+    env.insert("TERM".to_string(), None);
+    assert!(!sets_locale(Some(&env)));
+    env.insert("LC_CTYPE".to_string(), None);
+    assert!(sets_locale(Some(&env)));
+  }
+
+  #[test]
+  fn wait_respects_sync() {
+    with_runtime(async {
+      let vt = Arc::new(Mutex::new(Vt::new(5, 20, 0)));
+      vt.lock().unwrap().process(b"\x1b[?2026hhalf");
+      let half = |vt: &mut Vt| vt.screen().contents().contains("half");
+      assert!(wait_for(&vt, Some(100), |vt| half(vt).then_some(()))
+        .await
+        .is_ok());
+
+      vt.lock().unwrap().set_respect_sync(true);
+      assert!(wait_for(&vt, Some(100), |vt| half(vt).then_some(()))
+        .await
+        .is_err());
+      vt.lock().unwrap().process(b" frame\x1b[?2026l");
+      assert!(wait_for(&vt, Some(100), |vt| half(vt).then_some(()))
+        .await
+        .is_ok());
+    });
+  }
+
+  #[test]
+  fn wait_wakes_on_output() {
+    with_runtime(async {
+      let vt = Arc::new(Mutex::new(Vt::new(5, 20, 0)));
+      // Output processed during a check wakes the wait before the poll
+      // interval, which is longer than the timeout.
+      let mut checks = 0;
+      let result = wait_for(&vt, Some(40), |vt| {
+        checks += 1;
+        if checks == 1 {
+          vt.process(b"ready");
+          return None;
+        }
+        vt.screen().contents().contains("ready").then_some(())
+      })
+      .await;
+      assert!(result.is_ok());
+      assert_eq!(checks, 2);
+    });
+  }
+
+  #[test]
+  fn settle_screen() {
+    with_runtime(async {
+      let vt = Arc::new(Mutex::new(Vt::new(5, 20, 0)));
+      assert!(settle(&vt, 100).await.is_ok());
+
+      let spinner = {
+        let vt = vt.clone();
+        tokio::spawn(async move {
+          for frame in "|/-\\".chars().cycle() {
+            vt.lock()
+              .unwrap()
+              .process(format!("\r{}", frame).as_bytes());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+          }
+        })
+      };
+      assert!(settle(&vt, 100).await.is_err());
+      spinner.abort();
+    });
+  }
+
+  #[test]
+  fn shell_command() {
+    let argv = |kind, login| -> Result<Vec<String>> {
+      let cfg = ShellConfig {
+        kind,
+        login,
+        ..Default::default()
+      };
+      let cmd = cfg.command()?;
+      let argv = cmd.get_argv().iter();
+      Ok(argv.map(|a| a.to_string_lossy().into_owned()).collect())
+    };
+    assert_eq!(
+      argv(ShellKind::Bash, true).unwrap(),
+      ["bash", "--login", "--noprofile", "--norc", "-i"]
+    );
+    assert_eq!(argv(ShellKind::Zsh, false).unwrap(), ["zsh", "-f", "-i"]);
+    assert!(argv(ShellKind::Sh, true).is_err());
+  }
+
+  #[test]
+  fn paste_framing() {
+    let bytes = b"a\x1b\xff\x00\x1b[201~b";
+    assert_eq!(paste_frame(bytes, false), bytes);
+    assert_eq!(
+      paste_frame(bytes, true),
+      b"\x1b[200~a\x1b\xff\x00\x1b[201~b\x1b[201~"
+    );
+  }
+
+  #[test]
+  fn wait_row_captures() {
+    let captures = |pattern: &str, text: &str| {
+      let regex = regex::Regex::new(pattern).unwrap();
+      row_captures(&regex.captures(text).unwrap())
+    };
+    let some = |s: &str| Some(s.to_string());
+    assert_eq!(captures(r"\d+%", "[===] 42% done"), vec![some("42%")]);
+    assert_eq!(
+      captures(r"(\d+)/(\d+)(!)?", "3/10 files"),
+      vec![some("3"), some("10"), None]
+    );
+  }
+
+  #[test]
+  fn zero_size() {
+    let cfg = ProcConfig {
+      width: 0,
+      ..Default::default()
+    };
+    assert!(Proc::shell("true", &cfg).is_err());
+    let cfg = ProcConfig {
+      height: 0,
+      ..Default::default()
+    };
+    assert!(Proc::shell("true", &cfg).is_err());
+  }
+
+  #[test]
+  fn resize() {
+    with_runtime(async {
+      let mut proc = Proc::shell("sleep 100", &ProcConfig::default()).unwrap();
+      let size = |width, height| ResizeConfig { width, height };
+
+      assert!(proc.resize(size(0, 10)).is_err());
+      assert!(proc.resize(size(10, 0)).is_err());
+      assert_eq!(proc.lock_vt().unwrap().screen().size(), (30, 80));
+
+      assert!(!proc.resize(size(80, 30)).unwrap());
+      assert!(proc.resize(size(40, 10)).unwrap());
+      assert_eq!(proc.lock_vt().unwrap().screen().size(), (10, 40));
+      assert_eq!(proc.child().unwrap().master.get_size().unwrap().cols, 40);
+
+      proc.child_mut().unwrap().killer.kill().unwrap();
+    });
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn child_pids_of_shell() {
+    let mut child = std::process::Command::new("sh")
+      .args(["-c", "sleep 100 & sleep 100 & wait"])
+      .spawn()
+      .unwrap();
+    let pid = child.id() as i32;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while child_pids(pid).len() < 2 && Instant::now() < deadline {
+      std::thread::sleep(Duration::from_millis(10));
+    }
+    let pids = child_pids(pid);
+    assert_eq!(pids.len(), 2);
+    assert_eq!(child_pids_by_ppid(pid), pids);
+
+    for pid in pids {
+      unsafe { libc::kill(pid, libc::SIGKILL) };
+    }
+    child.wait().unwrap();
+  }
+
+  #[cfg(not(windows))]
+  #[test]
+  fn nice() {
+    with_runtime(async {
+      let cfg = ProcConfig {
+        nice: Some(7),
+        ..Default::default()
+      };
+      // `nice` without arguments prints its niceness, the one it got from
+      // the shell.
+      let proc = Proc::shell("nice", &cfg).unwrap();
+      let name = proc.log_name();
+      let exit = proc.child().unwrap().exit.clone();
+      Proc::wait(&name, exit).await.unwrap();
+      let eof = proc.child().unwrap().eof.clone();
+      Proc::wait_eof(eof, Some(5000)).await.unwrap();
+      assert_eq!(proc.vt.lock().unwrap().screen().contents(), "7");
+    });
+  }
+
+  #[test]
+  fn send_line_output() {
+    let prompt = ">>> ";
+    assert_eq!(line_output(b"1 + 1\r\n", "1 + 1", prompt), None);
+    assert_eq!(
+      line_output(b"1 + 1\r\n2\r\n\x1b[1m>>> \x1b[0m", "1 + 1", prompt),
+      Some("2\n".to_string())
+    );
+    // Without echo.
+    assert_eq!(
+      line_output(b"a\r\nb\r\n>>> ", "x", prompt),
+      Some("a\nb\n".to_string())
+    );
+  }
+
+  #[test]
+  fn cwd_resolution() {
+    let current_dir = std::env::current_dir().unwrap();
+    let script_dir = Some(Path::new("tests/scripts"));
+    let resolve = |cwd, base| resolve_cwd(cwd, base, script_dir).unwrap();
+
+    assert_eq!(resolve(None, CwdBase::Script), current_dir);
+    assert_eq!(
+      resolve(Some("fixtures"), CwdBase::Script),
+      current_dir.join("tests/scripts/fixtures")
+    );
+    assert_eq!(
+      resolve(Some("fixtures"), CwdBase::Process),
+      current_dir.join("fixtures")
+    );
+    assert_eq!(resolve(Some("/tmp"), CwdBase::Script), Path::new("/tmp"));
+    assert_eq!(
+      resolve_cwd(Some("fixtures"), CwdBase::Script, None).unwrap(),
+      current_dir.join("fixtures")
+    );
+  }
+
+  #[test]
+  fn env_values() {
+    let lua = Lua::new();
+    let cfg: ProcConfig = lua
+      .from_value(
+        lua
+          .load(r#"{ env = { A = "1", HOME = { inherit = true } } }"#)
+          .eval()
+          .unwrap(),
+      )
+      .unwrap();
+    let env = cfg.env.unwrap();
+    assert_eq!(env["A"], Some(EnvValue::Value("1".to_string())));
+    assert_eq!(env["HOME"], Some(EnvValue::Inherit { inherit: true }));
+  }
+
+  #[test]
+  fn wait_many_times() {
+    with_runtime(async {
+      let proc = Proc::shell("exit 3", &ProcConfig::default()).unwrap();
+      let first = Proc::wait("test", proc.child().unwrap().exit.clone());
+      let second = Proc::wait("test", proc.child().unwrap().exit.clone());
+      let (first, second) =
+        tokio::time::timeout(Duration::from_secs(5), async {
+          tokio::join!(first, second)
+        })
+        .await
+        .expect("process didn't exit");
+      assert_eq!(first.unwrap().exit_code(), 3);
+      assert_eq!(second.unwrap().exit_code(), 3);
+
+      // And after the exit.
+      let status = Proc::wait("test", proc.child().unwrap().exit.clone())
+        .await
+        .unwrap();
+      assert_eq!(status.exit_code(), 3);
+      assert!(matches!(proc.exit_status().unwrap(), Some(Ok(_))));
+    });
+  }
+}
@@ -0,0 +1,269 @@
+//! Timing of the `vt.*` functions and `proc:*` methods, enabled with
+//! `--trace-out`. The calls are written as a Chrome trace that Perfetto or
+//! `chrome://tracing` can show.
+
+use std::{
+  fmt::Write,
+  future::Future,
+  marker::PhantomData,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use mlua::{
+  FromLuaMulti, Function, Lua, ToLuaMulti, UserData, UserDataMethods,
+};
+
+use crate::{dump_json::write_json_str, fs_utils::write_atomic};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static PROFILE: Mutex<Profile> = Mutex::new(Profile::new());
+
+struct Profile {
+  started: Option<Instant>,
+  /// Names of the threads of the trace, one per script.
+  scripts: Vec<String>,
+  calls: Vec<Call>,
+}
+
+impl Profile {
+  const fn new() -> Self {
+    Profile {
+      started: None,
+      scripts: Vec::new(),
+      calls: Vec::new(),
+    }
+  }
+
+  fn to_json(&self) -> String {
+    let mut json = String::from("{\"traceEvents\":[");
+    let mut first = true;
+    let mut sep = |json: &mut String| {
+      if !std::mem::take(&mut first) {
+        json.push(',');
+      }
+    };
+    for (tid, script) in self.scripts.iter().enumerate() {
+      sep(&mut json);
+      let _ = write!(
+        json,
+        "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\
+         \"args\":{{\"name\":",
+        tid
+      );
+      write_json_str(&mut json, script);
+      json.push_str("}}");
+    }
+    for call in &self.calls {
+      sep(&mut json);
+      json.push_str("{\"name\":");
+      write_json_str(&mut json, &call.name);
+      let _ = write!(
+        json,
+        ",\"ph\":\"X\",\"pid\":1,\"tid\":{},\"ts\":{},\"dur\":{}}}",
+        call.tid,
+        call.start.as_micros(),
+        call.duration.as_micros()
+      );
+    }
+    json.push_str("]}\n");
+    json
+  }
+}
+
+struct Call {
+  name: String,
+  /// Index of the script.
+  tid: usize,
+  /// Time since profiling was enabled.
+  start: Duration,
+  duration: Duration,
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Profile> {
+  PROFILE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn enable() {
+  lock().started = Some(Instant::now());
+  ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Calls made after this are shown in a separate thread named `script`.
+pub fn start_script(script: &str) {
+  if ENABLED.load(Ordering::Relaxed) {
+    lock().scripts.push(script.to_string());
+  }
+}
+
+/// Writes the recorded calls as a Chrome trace.
+pub fn write(path: &str) -> Result<()> {
+  let json = lock().to_json();
+  write_atomic(path, |tmp| {
+    std::fs::write(tmp, json)?;
+    Ok(())
+  })
+}
+
+/// Records a call when dropped. `None` while profiling is off, so untraced
+/// calls only check a flag.
+pub struct Span {
+  name: String,
+  started: Instant,
+}
+
+pub fn span(name: &str) -> Option<Span> {
+  if !ENABLED.load(Ordering::Relaxed) {
+    return None;
+  }
+  Some(Span {
+    name: name.to_string(),
+    started: Instant::now(),
+  })
+}
+
+impl Drop for Span {
+  fn drop(&mut self) {
+    let mut profile = lock();
+    let start = match profile.started {
+      Some(started) => self.started.saturating_duration_since(started),
+      None => return,
+    };
+    let call = Call {
+      name: std::mem::take(&mut self.name),
+      tid: profile.scripts.len().saturating_sub(1),
+      start,
+      duration: self.started.elapsed(),
+    };
+    profile.calls.push(call);
+  }
+}
+
+/// `Lua::create_function()` with the calls timed as `name`.
+pub fn function<'lua, A, R, F>(
+  lua: &'lua Lua,
+  name: &'static str,
+  func: F,
+) -> mlua::Result<Function<'lua>>
+where
+  A: FromLuaMulti<'lua>,
+  R: ToLuaMulti<'lua>,
+  F: 'static + Fn(&'lua Lua, A) -> mlua::Result<R>,
+{
+  lua.create_function(move |lua, args| {
+    let _span = span(name);
+    func(lua, args)
+  })
+}
+
+/// `Lua::create_async_function()` with the calls timed as `name`, until the
+/// returned future completes.
+pub fn async_function<'lua, A, R, F, FR>(
+  lua: &'lua Lua,
+  name: &'static str,
+  func: F,
+) -> mlua::Result<Function<'lua>>
+where
+  A: FromLuaMulti<'lua>,
+  R: ToLuaMulti<'lua>,
+  F: 'static + Fn(&'lua Lua, A) -> FR,
+  FR: 'lua + Future<Output = mlua::Result<R>>,
+{
+  lua.create_async_function(move |lua, args| {
+    let span = span(name);
+    let result = func(lua, args);
+    async move {
+      let result = result.await;
+      drop(span);
+      result
+    }
+  })
+}
+
+/// Adds methods to `methods` with the calls timed as `<prefix>:<name>`.
+pub struct TimedMethods<'a, 'lua, T, M> {
+  methods: &'a mut M,
+  prefix: &'static str,
+  _marker: PhantomData<&'lua T>,
+}
+
+impl<'a, 'lua, T: UserData, M: UserDataMethods<'lua, T>>
+  TimedMethods<'a, 'lua, T, M>
+{
+  pub fn new(methods: &'a mut M, prefix: &'static str) -> Self {
+    TimedMethods {
+      methods,
+      prefix,
+      _marker: PhantomData,
+    }
+  }
+
+  fn name(&self, name: &str) -> String {
+    format!("{}:{}", self.prefix, name)
+  }
+
+  pub fn add_method<A, R, F>(&mut self, name: &str, method: F)
+  where
+    A: FromLuaMulti<'lua>,
+    R: ToLuaMulti<'lua>,
+    F: 'static + Fn(&'lua Lua, &T, A) -> mlua::Result<R>,
+  {
+    let span_name = self.name(name);
+    self.methods.add_method(name, move |lua, this, args| {
+      let _span = span(&span_name);
+      method(lua, this, args)
+    });
+  }
+
+  pub fn add_async_method<A, R, F, FR>(&mut self, name: &str, method: F)
+  where
+    T: Clone,
+    A: FromLuaMulti<'lua>,
+    R: ToLuaMulti<'lua>,
+    F: 'static + Fn(&'lua Lua, T, A) -> FR,
+    FR: 'lua + Future<Output = mlua::Result<R>>,
+  {
+    let span_name = self.name(name);
+    self.methods.add_async_method(name, move |lua, this, args| {
+      let span = span(&span_name);
+      let result = method(lua, this, args);
+      async move {
+        let result = result.await;
+        drop(span);
+        result
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn to_json() {
+    let profile = Profile {
+      started: None,
+      scripts: vec!["a.lua".to_string()],
+      calls: vec![Call {
+        name: "proc:wait_text".to_string(),
+        tid: 0,
+        start: Duration::from_micros(1500),
+        duration: Duration::from_millis(20),
+      }],
+    };
+    assert_eq!(
+      profile.to_json(),
+      "{\"traceEvents\":[\
+       {\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":0,\
+       \"args\":{\"name\":\"a.lua\"}},\
+       {\"name\":\"proc:wait_text\",\"ph\":\"X\",\"pid\":1,\"tid\":0,\
+       \"ts\":1500,\"dur\":20000}]}\n"
+    );
+  }
+}
@@ -0,0 +1,41 @@
+use std::sync::OnceLock;
+
+/// Input tracing mode set with the `VIRTERM_TRACE` environment variable:
+///
+/// - `1` - log every input sent to processes.
+/// - `dry` - log every input, but don't send it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceMode {
+  Off,
+  On,
+  DryRun,
+}
+
+pub fn mode() -> TraceMode {
+  static MODE: OnceLock<TraceMode> = OnceLock::new();
+  *MODE.get_or_init(|| {
+    let var = std::env::var("VIRTERM_TRACE").unwrap_or_default();
+    parse_mode(var.as_str())
+  })
+}
+
+fn parse_mode(var: &str) -> TraceMode {
+  match var {
+    "" | "0" => TraceMode::Off,
+    "dry" => TraceMode::DryRun,
+    _ => TraceMode::On,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    assert_eq!(parse_mode(""), TraceMode::Off);
+    assert_eq!(parse_mode("0"), TraceMode::Off);
+    assert_eq!(parse_mode("1"), TraceMode::On);
+    assert_eq!(parse_mode("dry"), TraceMode::DryRun);
+  }
+}
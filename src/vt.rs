@@ -0,0 +1,1485 @@
+use std::{
+  collections::VecDeque,
+  ops::Range,
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::watch;
+
+use crate::{
+  clock,
+  dump_png::Palette,
+  lint::{LintScanner, Stray},
+  margins::{CursorStyle, Margins},
+  osc::{self, apply_palette, color_reply, OscScanner},
+  proc::validate_size,
+};
+
+const SERIALIZED_MAGIC: &str = "virterm-screen-1";
+
+/// Distance between tab stops. vt100 has fixed tab stops and doesn't support
+/// setting them (HTS, TBC).
+pub const TAB_WIDTH: u16 = 8;
+
+/// Number of the most recent mode transitions to keep.
+const MAX_MODE_TRANSITIONS: usize = 1000;
+/// Number of the most recent title changes to keep.
+const MAX_TITLE_CHANGES: usize = 1000;
+/// Window in which `fps()` counts frames.
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+/// Terminal state fed by the pty reader: the vt100 parser plus what we track
+/// about the raw output stream.
+pub struct Vt {
+  parser: vt100::Parser,
+  /// The view scrolled back further than vt100 can show, see
+  /// `set_scrollback()`.
+  deep_page: Option<DeepPage>,
+
+  utf8: Utf8Scanner,
+  replacement_chars: usize,
+  /// The last `raw_capacity` bytes of the output.
+  raw: VecDeque<u8>,
+  raw_capacity: usize,
+  /// Keep the input in `raw` too, see `mark_input()`.
+  raw_input: bool,
+  /// Counters of `wait_text()` calls waiting on the output stream.
+  counters: Vec<(usize, StreamCounter)>,
+  next_counter: usize,
+  /// Output captured by `send_line_wait()` calls.
+  captures: Vec<(usize, Vec<u8>)>,
+  next_capture: usize,
+  /// Number of bells seen by the last `take_bell()`.
+  bells_seen: usize,
+  started: Instant,
+  modes: Modes,
+  mode_transitions: VecDeque<ModeTransition>,
+  title_changes: VecDeque<TitleChange>,
+  osc: OscScanner,
+  margins: Margins,
+  /// Reply to color queries, see `osc::color_reply()`.
+  color_replies: bool,
+  /// Colors redefined with OSC 4.
+  palette: Palette,
+  /// See `set_respect_sync()`.
+  respect_sync: bool,
+  /// Replies to queries waiting to be written to the process.
+  replies: Vec<u8>,
+  /// `None` until `track_cells()`, comparing the screen after every output
+  /// isn't free.
+  cell_times: Option<CellTimes>,
+  /// Cells changed since the last frame, see `fps()`.
+  frame_dirty: bool,
+  /// Times of the frames drawn in the last `FPS_WINDOW`.
+  frames: VecDeque<Instant>,
+  lint: LintScanner,
+  /// Regions watched by `proc:watch_region()`.
+  watches: Vec<Watch>,
+  next_watch: usize,
+  /// Changed after every `process()`, wakes the waits.
+  output: watch::Sender<()>,
+}
+
+/// A screen high page of the scrollback, copied from the rows `offset` rows up
+/// from the live screen.
+struct DeepPage {
+  offset: usize,
+  parser: vt100::Parser,
+}
+
+struct Watch {
+  id: usize,
+  rows: Range<u16>,
+  cols: Range<u16>,
+  since: Instant,
+}
+
+/// A terminal mode that changed its value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeTransition {
+  pub mode: &'static str,
+  pub value: String,
+  /// Milliseconds since the terminal was created.
+  pub time_ms: u128,
+}
+
+/// A window title set by the process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TitleChange {
+  pub title: String,
+  /// Milliseconds since the terminal was created.
+  pub time_ms: u128,
+}
+
+impl Vt {
+  pub fn new(rows: u16, cols: u16, scrollback: usize) -> Self {
+    let parser = vt100::Parser::new(rows, cols, scrollback);
+    Vt {
+      modes: Modes::from_screen(parser.screen()),
+      parser,
+      deep_page: None,
+
+      utf8: Utf8Scanner::default(),
+      replacement_chars: 0,
+      raw: VecDeque::new(),
+      raw_capacity: 0,
+      raw_input: false,
+      counters: Vec::new(),
+      next_counter: 0,
+      captures: Vec::new(),
+      next_capture: 0,
+      bells_seen: 0,
+      started: Instant::now(),
+      mode_transitions: VecDeque::new(),
+      title_changes: VecDeque::new(),
+      osc: OscScanner::default(),
+      margins: Margins::new(rows),
+      color_replies: false,
+      palette: Palette::default(),
+      respect_sync: false,
+      replies: Vec::new(),
+      cell_times: None,
+      frame_dirty: false,
+      frames: VecDeque::new(),
+      lint: LintScanner::default(),
+      watches: Vec::new(),
+      next_watch: 0,
+      output: watch::channel(()).0,
+    }
+  }
+
+  /// Feeds output of the process. Returns modes that changed. As modes are
+  /// compared after each call, a mode toggled back and forth within `bytes`
+  /// isn't reported.
+  pub fn process(&mut self, bytes: &[u8]) -> Vec<ModeTransition> {
+    self.replacement_chars += self.utf8.scan(bytes);
+    self.keep_raw(bytes);
+    for (_, counter) in self.counters.iter_mut() {
+      counter.feed(bytes);
+    }
+    for (_, capture) in self.captures.iter_mut() {
+      capture.extend_from_slice(bytes);
+    }
+    let time_ms = clock::elapsed(self.started).as_millis();
+    for osc in self.osc.scan(bytes) {
+      if let Some(title) = osc::title(&osc) {
+        self.push_title(title, time_ms);
+      }
+      apply_palette(&osc, &mut self.palette);
+      if self.color_replies {
+        if let Some(reply) = color_reply(&osc, &self.palette) {
+          self.replies.extend(reply);
+        }
+      }
+    }
+    self.margins.scan(bytes);
+    self.lint.scan(bytes);
+    // vt100 keeps a scrolled back view in place when new rows scroll, by
+    // moving its offset. A deep page is followed the same way.
+    if self.deep_page.is_some() {
+      self.parser.set_scrollback(1);
+    }
+    self.parser.process(bytes);
+    let moved = self.parser.screen().scrollback();
+    let offset = match &self.deep_page {
+      Some(page) => page.offset + moved.saturating_sub(1),
+      None => moved,
+    };
+    if self.deep_page.is_some()
+      || offset > self.parser.screen().size().0 as usize
+    {
+      self.set_scrollback(offset);
+    }
+    if let Some(cell_times) = &mut self.cell_times {
+      let now = Instant::now();
+      self.frame_dirty |= cell_times.update(self.parser.screen(), now);
+      if self.frame_dirty && !self.synchronizing() {
+        self.frame_dirty = false;
+        self.frames.push_back(now);
+        self.trim_frames();
+      }
+    }
+
+    // Allocates only for the modes that changed.
+    let modes = Modes::from_screen(self.parser.screen());
+    let transitions: Vec<_> = self
+      .modes
+      .diff(&modes)
+      .into_iter()
+      .map(|(mode, value)| ModeTransition {
+        mode,
+        value,
+        time_ms,
+      })
+      .collect();
+    self.modes = modes;
+    for transition in &transitions {
+      if self.mode_transitions.len() == MAX_MODE_TRANSITIONS {
+        self.mode_transitions.pop_front();
+      }
+      self.mode_transitions.push_back(transition.clone());
+    }
+    self.output.send_replace(());
+    transitions
+  }
+
+  /// Receives a change after every call of `process()`.
+  pub fn output_changes(&self) -> watch::Receiver<()> {
+    self.output.subscribe()
+  }
+
+  pub fn set_color_replies(&mut self, enabled: bool) {
+    self.color_replies = enabled;
+  }
+
+  /// Takes replies to the queries of the process, they should be written to
+  /// its input.
+  pub fn take_replies(&mut self) -> Vec<u8> {
+    std::mem::take(&mut self.replies)
+  }
+
+  /// Titles set so far, up to `MAX_TITLE_CHANGES` most recent ones. Setting
+  /// the same title again isn't a change.
+  pub fn title_changes(&self) -> &VecDeque<TitleChange> {
+    &self.title_changes
+  }
+
+  fn push_title(&mut self, title: String, time_ms: u128) {
+    let last = self.title_changes.back().map_or("", |c| c.title.as_str());
+    if title == last {
+      return;
+    }
+    if self.title_changes.len() == MAX_TITLE_CHANGES {
+      self.title_changes.pop_front();
+    }
+    self.title_changes.push_back(TitleChange { title, time_ms });
+  }
+
+  /// Mode changes so far, up to `MAX_MODE_TRANSITIONS` most recent ones.
+  pub fn mode_transitions(&self) -> &VecDeque<ModeTransition> {
+    &self.mode_transitions
+  }
+
+  /// Starts counting occurrences of `text` in the output. Returns the counter
+  /// id for `counter()` and `remove_counter()`.
+  pub fn add_counter(&mut self, text: &str) -> usize {
+    let id = self.next_counter;
+    self.next_counter += 1;
+    self.counters.push((id, StreamCounter::new(text)));
+    id
+  }
+
+  pub fn counter(&self, id: usize) -> usize {
+    self
+      .counters
+      .iter()
+      .find(|(counter_id, _)| *counter_id == id)
+      .map_or(0, |(_, counter)| counter.count)
+  }
+
+  pub fn remove_counter(&mut self, id: usize) {
+    self.counters.retain(|(counter_id, _)| *counter_id != id);
+  }
+
+  /// Starts keeping the last `capacity` bytes of the raw output. Zero turns
+  /// it off.
+  pub fn set_raw_capacity(&mut self, capacity: usize) {
+    self.raw_capacity = capacity;
+    self.trim_raw();
+  }
+
+  pub fn raw_capacity(&self) -> usize {
+    self.raw_capacity
+  }
+
+  pub fn set_raw_input(&mut self, enabled: bool) {
+    self.raw_input = enabled;
+  }
+
+  /// Keeps input sent to the process in the raw buffer, between the output
+  /// around it. It's kept as an APC string that terminals ignore, so the raw
+  /// output can still be replayed: `ESC _ virterm-input;<bytes> ESC \`, with
+  /// the bytes escaped like Rust byte strings.
+  pub fn mark_input(&mut self, bytes: &[u8]) {
+    if self.raw_input {
+      let marker = format!("\x1b_virterm-input;{}\x1b\\", bytes.escape_ascii());
+      self.keep_raw(marker.as_bytes());
+    }
+  }
+
+  /// Up to `len` most recent bytes of the raw output.
+  pub fn raw_tail(&self, len: usize) -> Vec<u8> {
+    let skip = self.raw.len().saturating_sub(len);
+    self.raw.iter().skip(skip).copied().collect()
+  }
+
+  /// Non-overlapping occurrences of `text` in the kept raw output.
+  pub fn count_raw(&self, text: &str) -> usize {
+    let mut counter = StreamCounter::new(text);
+    let (front, back) = self.raw.as_slices();
+    counter.feed(front);
+    counter.feed(back);
+    counter.count
+  }
+
+  fn keep_raw(&mut self, bytes: &[u8]) {
+    if self.raw_capacity == 0 {
+      return;
+    }
+    let skip = bytes.len().saturating_sub(self.raw_capacity);
+    self.raw.extend(&bytes[skip..]);
+    self.trim_raw();
+  }
+
+  fn trim_raw(&mut self) {
+    let excess = self.raw.len().saturating_sub(self.raw_capacity);
+    self.raw.drain(..excess);
+  }
+
+  /// The screen as shown, scrolled back with `set_scrollback()`.
+  pub fn screen(&self) -> &vt100::Screen {
+    match &self.deep_page {
+      Some(page) => page.parser.screen(),
+      None => self.parser.screen(),
+    }
+  }
+
+  /// Rows the screen is scrolled back, see `set_scrollback()`.
+  pub fn scrollback_offset(&self) -> usize {
+    match &self.deep_page {
+      Some(page) => page.offset,
+      None => self.parser.screen().scrollback(),
+    }
+  }
+
+  /// Shows the screen `offset` rows up in the scrollback, 0 is the live
+  /// screen. The offset is limited to the scrollback length.
+  pub fn set_scrollback(&mut self, offset: usize) {
+    let rows = self.parser.screen().size().0 as usize;
+    self.parser.set_scrollback(usize::MAX);
+    let offset = offset
+      .min(self.parser.screen().scrollback())
+      .min(u16::MAX as usize);
+    // vt100 panics when reading a screen scrolled back further than its
+    // height, deeper pages are copied from a taller screen.
+    if offset <= rows {
+      self.deep_page = None;
+      self.parser.set_scrollback(offset);
+    } else {
+      self.deep_page = Some(self.deep_page(offset));
+    }
+  }
+
+  /// Copies the page `offset` rows up, the offset is more than the screen
+  /// height and at most the scrollback length.
+  fn deep_page(&mut self, offset: usize) -> DeepPage {
+    let (rows, cols) = self.parser.screen().size();
+    // A screen as tall as the offset scrolled back by it shows only the
+    // scrollback, the page is at its top. The added rows are blank and get
+    // removed when the size is restored, as in `with_scrollback()`.
+    let height = offset as u16;
+    self.parser.set_size(height, cols);
+    self.parser.set_scrollback(offset);
+    let contents = self.parser.screen().contents_formatted();
+    self.parser.set_size(rows, cols);
+    self.parser.set_scrollback(0);
+
+    let live = self.parser.screen();
+    let mut parser = vt100::Parser::new(height, cols, 0);
+    parser.process(&contents);
+    parser.set_size(rows, cols);
+    // Everything but the cells stays as on the live screen.
+    parser.process(&live.input_mode_formatted());
+    parser.process(&live.title_formatted());
+    parser.process(&live.cursor_state_formatted());
+    DeepPage { offset, parser }
+  }
+
+  pub fn set_size(&mut self, rows: u16, cols: u16) {
+    let offset = self.scrollback_offset();
+    self.parser.set_size(rows, cols);
+    // Keeps the view on the same rows, or on the page of them if the new
+    // height can't show them.
+    if offset > 0 {
+      self.set_scrollback(offset);
+    }
+    self.margins.set_rows(rows);
+    if let Some(cell_times) = &mut self.cell_times {
+      cell_times.update(self.parser.screen(), Instant::now());
+    }
+  }
+
+  /// Starts tracking when cells change, for `cell_changed_ago()`, `fps()`
+  /// and the watches. Does nothing if they are tracked already.
+  pub fn track_cells(&mut self) {
+    if self.cell_times.is_none() {
+      let mut cell_times = CellTimes::default();
+      cell_times.update(self.parser.screen(), Instant::now());
+      self.cell_times = Some(cell_times);
+    }
+  }
+
+  pub fn tracks_cells(&self) -> bool {
+    self.cell_times.is_some()
+  }
+
+  /// Time since the content or attributes of a cell last changed, or since
+  /// the tracking started or the terminal was resized. `None` outside of the
+  /// screen or if cells aren't tracked.
+  pub fn cell_changed_ago(&self, row: u16, col: u16) -> Option<Duration> {
+    let changed = self.cell_times.as_ref()?.changed(row, col)?;
+    // Real time, like the frames of `fps()`.
+    Some(changed.elapsed())
+  }
+
+  /// Stray control characters found in the output so far, see `lint`, and
+  /// the number of all of them.
+  pub fn stray_controls(&self) -> (&[Stray], usize) {
+    (self.lint.stray(), self.lint.count())
+  }
+
+  /// Starts watching the cells in `rows` and `cols` for changes, tracking
+  /// the cells from now on. Returns the watch id for `watch_changes()`.
+  pub fn add_watch(&mut self, rows: Range<u16>, cols: Range<u16>) -> usize {
+    self.track_cells();
+    let id = self.next_watch;
+    self.next_watch += 1;
+    self.watches.push(Watch {
+      id,
+      rows,
+      cols,
+      since: Instant::now(),
+    });
+    id
+  }
+
+  /// Cells (row, col) of the watch that changed since it started, `None` for
+  /// an unknown watch. A resize changes all cells. Cells outside of the
+  /// screen are skipped.
+  pub fn watch_changes(&self, id: usize) -> Option<Vec<(u16, u16)>> {
+    let watch = self.watches.iter().find(|watch| watch.id == id)?;
+    let cell_times = self.cell_times.as_ref()?;
+    let mut changes = Vec::new();
+    for row in watch.rows.clone() {
+      for col in watch.cols.clone() {
+        match cell_times.changed(row, col) {
+          Some(changed) if changed > watch.since => changes.push((row, col)),
+          _ => (),
+        }
+      }
+    }
+    Some(changes)
+  }
+
+  /// Frames drawn in the last second. A frame is the output read in one go
+  /// that changed the visible cells, or the whole update between the start
+  /// and the end of synchronized output. Only counted while cells are
+  /// tracked.
+  pub fn fps(&mut self) -> usize {
+    self.trim_frames();
+    self.frames.len()
+  }
+
+  fn trim_frames(&mut self) {
+    while let Some(time) = self.frames.front() {
+      // Real time, frames must be trimmed in the deterministic mode too.
+      if time.elapsed() <= FPS_WINDOW {
+        break;
+      }
+      self.frames.pop_front();
+    }
+  }
+
+  /// Palette colors redefined by the process with OSC 4.
+  pub fn palette(&self) -> &Palette {
+    &self.palette
+  }
+
+  /// First and last row (from 0) of the scrolling region set by the process.
+  pub fn scroll_region(&self) -> (u16, u16) {
+    self.margins.scroll_region()
+  }
+
+  /// Whether the cursor is positioned relative to the scrolling region.
+  pub fn origin_mode(&self) -> bool {
+    self.margins.origin_mode()
+  }
+
+  /// Whether the process is in the middle of drawing a frame with
+  /// synchronized output (mode 2026). The screen may show a partial frame.
+  pub fn synchronizing(&self) -> bool {
+    self.margins.synchronized()
+  }
+
+  pub fn cursor_style(&self) -> CursorStyle {
+    self.margins.cursor_style()
+  }
+
+  /// Makes waits check the screen only between synchronized frames.
+  pub fn set_respect_sync(&mut self, enabled: bool) {
+    self.respect_sync = enabled;
+  }
+
+  pub fn respect_sync(&self) -> bool {
+    self.respect_sync
+  }
+
+  /// Serializes the screen state (size, cursor, contents and modes), so
+  /// `Vt::deserialize()` can restore it without the process.
+  pub fn serialize(&self) -> Vec<u8> {
+    let screen = self.screen();
+    let (rows, cols) = screen.size();
+    let mut blob =
+      format!("{} {} {}\n", SERIALIZED_MAGIC, rows, cols).into_bytes();
+    blob.extend(screen.state_formatted());
+    blob
+  }
+
+  pub fn deserialize(blob: &[u8]) -> Result<Self> {
+    let header_end = blob
+      .iter()
+      .position(|b| *b == b'\n')
+      .context("Serialized screen has no header")?;
+    let header = std::str::from_utf8(&blob[..header_end])?;
+    let (rows, cols) = match header.split(' ').collect::<Vec<_>>()[..] {
+      [SERIALIZED_MAGIC, rows, cols] => (rows.parse()?, cols.parse()?),
+      _ => bail!("Not a serialized screen"),
+    };
+    // The header may come from anywhere.
+    validate_size(cols, rows)?;
+
+    let mut vt = Vt::new(rows, cols, 0);
+    vt.process(&blob[header_end + 1..]);
+    Ok(vt)
+  }
+
+  /// Hash of the visible cells: their content and attributes. The hash is
+  /// stable between runs and virterm builds.
+  pub fn screen_hash(&self) -> u64 {
+    let screen = self.screen();
+    let mut hasher = Fnv1a::default();
+    let (rows, cols) = screen.size();
+    hasher.write(&rows.to_le_bytes());
+    hasher.write(&cols.to_le_bytes());
+    for row in 0..rows {
+      for col in 0..cols {
+        let cell = match screen.cell(row, col) {
+          Some(cell) => cell,
+          None => continue,
+        };
+        hasher.write(cell.contents().as_bytes());
+        hasher.write(&[0]);
+        hash_color(&mut hasher, cell.fgcolor());
+        hash_color(&mut hasher, cell.bgcolor());
+        let attrs = cell.bold() as u8
+          | (cell.italic() as u8) << 1
+          | (cell.underline() as u8) << 2
+          | (cell.inverse() as u8) << 3;
+        hasher.write(&[attrs]);
+      }
+    }
+    hasher.0
+  }
+
+  /// Starts capturing the raw output. Returns the capture id for
+  /// `captured()` and `stop_capture()`.
+  pub fn start_capture(&mut self) -> usize {
+    let id = self.next_capture;
+    self.next_capture += 1;
+    self.captures.push((id, Vec::new()));
+    id
+  }
+
+  pub fn captured(&self, id: usize) -> &[u8] {
+    self
+      .captures
+      .iter()
+      .find(|(capture_id, _)| *capture_id == id)
+      .map_or(&[], |(_, capture)| capture.as_slice())
+  }
+
+  pub fn stop_capture(&mut self, id: usize) -> Vec<u8> {
+    match self
+      .captures
+      .iter()
+      .position(|(capture_id, _)| *capture_id == id)
+    {
+      Some(idx) => self.captures.remove(idx).1,
+      None => Vec::new(),
+    }
+  }
+
+  /// Cells of up to `max_scrollback` rows of the scrollback, oldest first,
+  /// followed by the visible rows.
+  pub fn rows_with_scrollback(
+    &mut self,
+    max_scrollback: usize,
+  ) -> Vec<Vec<Option<vt100::Cell>>> {
+    self.with_scrollback(max_scrollback, |screen| {
+      let (rows, cols) = screen.size();
+      (0..rows)
+        .map(|row| {
+          (0..cols)
+            .map(|col| screen.cell(row, col).cloned())
+            .collect()
+        })
+        .collect()
+    })
+  }
+
+  /// Like `Screen::contents()`, with the whole scrollback above the visible
+  /// rows. The same as the screen contents when the scrollback is empty.
+  pub fn contents_with_scrollback(&mut self) -> String {
+    self.with_scrollback(usize::MAX, |screen| screen.contents())
+  }
+
+  /// Calls `f` with a screen of up to `max_scrollback` rows of the scrollback
+  /// followed by the visible rows.
+  fn with_scrollback<T>(
+    &mut self,
+    max_scrollback: usize,
+    f: impl FnOnce(&vt100::Screen) -> T,
+  ) -> T {
+    let offset = self.parser.screen().scrollback();
+    let (rows, cols) = self.parser.screen().size();
+    self.parser.set_scrollback(usize::MAX);
+    let len = self
+      .screen()
+      .scrollback()
+      .min(max_scrollback)
+      .min((u16::MAX - rows) as usize);
+
+    // vt100 can't scroll back further than the screen height, so the screen
+    // is made taller for a moment to show all the rows at once. The added
+    // rows are blank and get removed when the size is restored.
+    self.parser.set_size(rows + len as u16, cols);
+    self.parser.set_scrollback(len);
+    let result = f(self.parser.screen());
+
+    self.parser.set_size(rows, cols);
+    self.parser.set_scrollback(offset);
+    result
+  }
+
+  /// Number of visible cells with something besides whitespace. A wide char
+  /// counts once. Attributes are ignored, so a colored blank cell isn't
+  /// counted.
+  pub fn non_blank_cells(&self) -> usize {
+    let screen = self.screen();
+    let (rows, cols) = screen.size();
+    let mut count = 0;
+    for row in 0..rows {
+      for col in 0..cols {
+        if let Some(cell) = screen.cell(row, col) {
+          if !cell.is_wide_continuation()
+            && !cell.contents().chars().all(char::is_whitespace)
+          {
+            count += 1;
+          }
+        }
+      }
+    }
+    count
+  }
+
+  /// Whether a bell, audible (BEL) or visual (`ESC g`), rang since the
+  /// previous call.
+  pub fn take_bell(&mut self) -> bool {
+    let screen = self.screen();
+    let bells = screen.audible_bell_count() + screen.visual_bell_count();
+    let rang = bells != self.bells_seen;
+    self.bells_seen = bells;
+    rang
+  }
+
+  /// Lines as printed by the app: rows that wrapped into the next row are
+  /// joined. Trailing empty lines are dropped like in `contents()`.
+  pub fn logical_lines(&self) -> Vec<String> {
+    let screen = self.screen();
+    let (_rows, cols) = screen.size();
+    let mut lines: Vec<String> = Vec::new();
+    let mut wrapping = false;
+    for (row, text) in screen.rows(0, cols).enumerate() {
+      if wrapping {
+        if let Some(line) = lines.last_mut() {
+          line.push_str(&text);
+        }
+      } else {
+        lines.push(text);
+      }
+      wrapping = screen.row_wrapped(row as u16);
+    }
+    while lines.last().is_some_and(|line| line.is_empty()) {
+      lines.pop();
+    }
+    lines
+  }
+
+  /// Number of U+FFFD characters produced by the output so far. Both invalid
+  /// utf-8 sequences and replacement characters sent as is are counted.
+  pub fn replacement_chars(&self) -> usize {
+    self.replacement_chars
+  }
+}
+
+/// Terminal modes set by the process that affect how input is encoded or the
+/// screen is shown.
+#[derive(Clone, Debug, PartialEq)]
+struct Modes {
+  alternate_screen: bool,
+  application_keypad: bool,
+  application_cursor: bool,
+  hide_cursor: bool,
+  bracketed_paste: bool,
+  mouse_protocol_mode: vt100::MouseProtocolMode,
+  mouse_protocol_encoding: vt100::MouseProtocolEncoding,
+}
+
+impl Modes {
+  fn from_screen(screen: &vt100::Screen) -> Self {
+    Modes {
+      alternate_screen: screen.alternate_screen(),
+      application_keypad: screen.application_keypad(),
+      application_cursor: screen.application_cursor(),
+      hide_cursor: screen.hide_cursor(),
+      bracketed_paste: screen.bracketed_paste(),
+      mouse_protocol_mode: screen.mouse_protocol_mode(),
+      mouse_protocol_encoding: screen.mouse_protocol_encoding(),
+    }
+  }
+
+  /// Names and new values of the modes that differ in `new`.
+  fn diff(&self, new: &Modes) -> Vec<(&'static str, String)> {
+    let mut changes = Vec::new();
+    let mut flag = |name, old: bool, new: bool| {
+      if old != new {
+        changes.push((name, if new { "on" } else { "off" }.to_string()));
+      }
+    };
+    flag(
+      "alternate_screen",
+      self.alternate_screen,
+      new.alternate_screen,
+    );
+    flag(
+      "application_keypad",
+      self.application_keypad,
+      new.application_keypad,
+    );
+    flag(
+      "application_cursor",
+      self.application_cursor,
+      new.application_cursor,
+    );
+    flag("hide_cursor", self.hide_cursor, new.hide_cursor);
+    flag("bracketed_paste", self.bracketed_paste, new.bracketed_paste);
+    if self.mouse_protocol_mode != new.mouse_protocol_mode {
+      let mode = format!("{:?}", new.mouse_protocol_mode);
+      changes.push(("mouse_protocol_mode", mode));
+    }
+    if self.mouse_protocol_encoding != new.mouse_protocol_encoding {
+      let encoding = format!("{:?}", new.mouse_protocol_encoding);
+      changes.push(("mouse_protocol_encoding", encoding));
+    }
+    changes
+  }
+}
+
+/// Removes escape sequences (CSI, OSC, DCS and two byte ones) from the raw
+/// output, leaving the printed text and control characters.
+pub fn strip_escapes(bytes: &[u8]) -> Vec<u8> {
+  let mut text = Vec::with_capacity(bytes.len());
+  let mut iter = bytes.iter().copied().peekable();
+  while let Some(byte) = iter.next() {
+    if byte != 0x1b {
+      text.push(byte);
+      continue;
+    }
+    match iter.next() {
+      // CSI: parameters and intermediates up to a final byte.
+      Some(b'[') => {
+        for byte in iter.by_ref() {
+          if (0x40..=0x7e).contains(&byte) {
+            break;
+          }
+        }
+      }
+      // OSC, DCS, APC, PM, SOS: up to BEL or ST.
+      Some(b']' | b'P' | b'_' | b'^' | b'X') => {
+        while let Some(byte) = iter.next() {
+          if byte == 0x07 {
+            break;
+          }
+          if byte == 0x1b && iter.peek() == Some(&b'\\') {
+            iter.next();
+            break;
+          }
+        }
+      }
+      // Charset designation has one more byte.
+      Some(b'(' | b')' | b'*' | b'+') => {
+        iter.next();
+      }
+      _ => (),
+    }
+  }
+  text
+}
+
+/// Describes how screen `b` differs from screen `a`: the size and up to
+/// `limit` cells, with their text and attributes. `None` if they are the
+/// same. Cells are compared like in `dump_png::Renderer::render_diff()`.
+pub fn screen_diff(
+  a: &vt100::Screen,
+  b: &vt100::Screen,
+  limit: usize,
+) -> Option<String> {
+  let mut lines = Vec::new();
+  let ((a_rows, a_cols), (b_rows, b_cols)) = (a.size(), b.size());
+  if (a_rows, a_cols) != (b_rows, b_cols) {
+    lines.push(format!(
+      "size: {}x{} -> {}x{}",
+      a_cols, a_rows, b_cols, b_rows
+    ));
+  }
+  let mut cells = 0;
+  for row in 0..a_rows.max(b_rows) {
+    for col in 0..a_cols.max(b_cols) {
+      let (a_cell, b_cell) = (a.cell(row, col), b.cell(row, col));
+      if a_cell == b_cell {
+        continue;
+      }
+      cells += 1;
+      if cells <= limit {
+        lines.push(format!(
+          "x={} y={}: {} -> {}",
+          col,
+          row,
+          describe_cell(a_cell),
+          describe_cell(b_cell)
+        ));
+      }
+    }
+  }
+  if cells > limit {
+    lines.push(format!("... {} more cells", cells - limit));
+  }
+  if lines.is_empty() {
+    return None;
+  }
+  let summary = match cells {
+    1 => "1 cell differs".to_string(),
+    cells => format!("{} cells differ", cells),
+  };
+  Some(format!("{}\n  {}", summary, lines.join("\n  ")))
+}
+
+/// E.g. `"a" bold fg=1 bg=#102030`, or `none` outside of the screen.
+fn describe_cell(cell: Option<&vt100::Cell>) -> String {
+  let cell = match cell {
+    Some(cell) => cell,
+    None => return "none".to_string(),
+  };
+  let mut text = format!("{:?}", cell.contents());
+  for (attr, set) in [
+    ("bold", cell.bold()),
+    ("italic", cell.italic()),
+    ("underline", cell.underline()),
+    ("inverse", cell.inverse()),
+  ] {
+    if set {
+      text.push(' ');
+      text.push_str(attr);
+    }
+  }
+  for (name, color) in [("fg", cell.fgcolor()), ("bg", cell.bgcolor())] {
+    match color {
+      vt100::Color::Default => (),
+      vt100::Color::Idx(idx) => text.push_str(&format!(" {}={}", name, idx)),
+      vt100::Color::Rgb(r, g, b) => {
+        text.push_str(&format!(" {}=#{:02x}{:02x}{:02x}", name, r, g, b))
+      }
+    }
+  }
+  text
+}
+
+/// When each visible cell last changed, updated after each output.
+#[derive(Default)]
+struct CellTimes {
+  cols: u16,
+  cells: Vec<Option<vt100::Cell>>,
+  changed: Vec<Instant>,
+}
+
+impl CellTimes {
+  /// Returns `true` if any cell changed.
+  fn update(&mut self, screen: &vt100::Screen, now: Instant) -> bool {
+    let (rows, cols) = screen.size();
+    let len = rows as usize * cols as usize;
+    let mut any = false;
+    if cols != self.cols || self.cells.len() != len {
+      self.cols = cols;
+      self.cells = vec![None; len];
+      self.changed = vec![now; len];
+      any = true;
+    }
+    for row in 0..rows {
+      for col in 0..cols {
+        let i = row as usize * cols as usize + col as usize;
+        let cell = screen.cell(row, col);
+        if self.cells[i].as_ref() != cell {
+          self.cells[i] = cell.cloned();
+          self.changed[i] = now;
+          any = true;
+        }
+      }
+    }
+    any
+  }
+
+  fn changed(&self, row: u16, col: u16) -> Option<Instant> {
+    if col >= self.cols {
+      return None;
+    }
+    let i = row as usize * self.cols as usize + col as usize;
+    self.changed.get(i).copied()
+  }
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output never changes, so
+/// hashes can be compared across runs.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+  fn default() -> Self {
+    Fnv1a(0xcbf29ce484222325)
+  }
+}
+
+impl Fnv1a {
+  fn write(&mut self, bytes: &[u8]) {
+    for byte in bytes {
+      self.0 ^= *byte as u64;
+      self.0 = self.0.wrapping_mul(0x100000001b3);
+    }
+  }
+}
+
+fn hash_color(hasher: &mut Fnv1a, color: vt100::Color) {
+  match color {
+    vt100::Color::Default => hasher.write(&[0]),
+    vt100::Color::Idx(idx) => hasher.write(&[1, idx]),
+    vt100::Color::Rgb(r, g, b) => hasher.write(&[2, r, g, b]),
+  }
+}
+
+/// Counts non-overlapping occurrences of a text in the output, including ones
+/// split between reads.
+struct StreamCounter {
+  needle: Vec<u8>,
+  /// End of the previous read that may be the start of an occurrence.
+  tail: Vec<u8>,
+  count: usize,
+}
+
+impl StreamCounter {
+  fn new(text: &str) -> Self {
+    StreamCounter {
+      needle: text.as_bytes().to_vec(),
+      tail: Vec::new(),
+      count: 0,
+    }
+  }
+
+  fn feed(&mut self, bytes: &[u8]) {
+    let len = self.needle.len();
+    if len == 0 {
+      return;
+    }
+    let mut buf = std::mem::take(&mut self.tail);
+    buf.extend_from_slice(bytes);
+
+    let mut pos = 0;
+    while pos + len <= buf.len() {
+      if buf[pos..pos + len] == self.needle[..] {
+        self.count += 1;
+        pos += len;
+      } else {
+        pos += 1;
+      }
+    }
+    let keep_from = pos.max(buf.len().saturating_sub(len - 1));
+    self.tail = buf.split_off(keep_from);
+  }
+}
+
+/// Decodes utf-8 across read boundaries. A sequence split between two reads
+/// is kept until the next read instead of being counted as invalid.
+#[derive(Default)]
+struct Utf8Scanner {
+  pending: Vec<u8>,
+}
+
+impl Utf8Scanner {
+  /// Returns the number of replacement characters in `bytes`.
+  fn scan(&mut self, bytes: &[u8]) -> usize {
+    let mut buf = std::mem::take(&mut self.pending);
+    buf.extend_from_slice(bytes);
+
+    let mut count = 0;
+    let mut rest = &buf[..];
+    loop {
+      match std::str::from_utf8(rest) {
+        Ok(valid) => {
+          count += valid.matches('\u{FFFD}').count();
+          break;
+        }
+        Err(err) => {
+          let (valid, invalid) = rest.split_at(err.valid_up_to());
+          // Safe to unwrap: `valid_up_to()` is the end of the valid prefix.
+          let valid = std::str::from_utf8(valid).unwrap();
+          count += valid.matches('\u{FFFD}').count();
+          match err.error_len() {
+            Some(len) => {
+              count += 1;
+              rest = &invalid[len..];
+            }
+            None => {
+              self.pending = invalid.to_vec();
+              break;
+            }
+          }
+        }
+      }
+    }
+    count
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn replacement_chars() {
+    let mut scanner = Utf8Scanner::default();
+    assert_eq!(scanner.scan(b"hello"), 0);
+    assert_eq!(scanner.scan("\u{FFFD}".as_bytes()), 1);
+    assert_eq!(scanner.scan(b"a\xffb\xfe"), 2);
+
+    // "é" split between two reads.
+    assert_eq!(scanner.scan(b"\xc3"), 0);
+    assert_eq!(scanner.scan(b"\xa9"), 0);
+
+    // Incomplete sequence followed by a valid char.
+    assert_eq!(scanner.scan(b"\xc3"), 0);
+    assert_eq!(scanner.scan(b"a"), 1);
+  }
+
+  #[test]
+  fn serialize() {
+    let mut vt = Vt::new(5, 20, 0);
+    vt.process(b"\x1b]2;title\x07hello\r\n\x1b[31mworld\x1b[5;3H\x1b[?25l");
+
+    let restored = Vt::deserialize(&vt.serialize()).unwrap();
+    assert_eq!(restored.screen().size(), (5, 20));
+    assert_eq!(restored.screen().contents(), vt.screen().contents());
+    assert_eq!(restored.screen().cursor_position(), (4, 2));
+    assert!(restored.screen().hide_cursor());
+    assert_eq!(restored.screen().title(), "title");
+    assert_eq!(restored.screen_hash(), vt.screen_hash());
+
+    assert!(Vt::deserialize(b"hello").is_err());
+    assert!(Vt::deserialize(b"hello\n").is_err());
+    let err = |blob: &[u8]| Vt::deserialize(blob).err().unwrap().to_string();
+    assert_eq!(
+      err(b"virterm-screen-1 0 0\n"),
+      "Terminal size must be at least 1x1, got 0x0"
+    );
+    assert_eq!(
+      err(b"virterm-screen-1 65535 65535\nx"),
+      "Terminal size must be at most 1048576 cells, got 65535x65535"
+    );
+  }
+
+  #[test]
+  fn screen_hash() {
+    let mut a = Vt::new(2, 10, 0);
+    let mut b = Vt::new(2, 10, 0);
+    assert_eq!(a.screen_hash(), b.screen_hash());
+
+    a.process(b"hello");
+    assert_ne!(a.screen_hash(), b.screen_hash());
+    b.process(b"hello");
+    assert_eq!(a.screen_hash(), b.screen_hash());
+
+    // Attributes change the hash too.
+    a.process(b"\x1b[1m!");
+    b.process(b"!");
+    assert_ne!(a.screen_hash(), b.screen_hash());
+  }
+
+  #[test]
+  fn scroll_region() {
+    let mut vt = Vt::new(24, 80, 0);
+    assert_eq!(vt.scroll_region(), (0, 23));
+    vt.process(b"\x1b[5;20r\x1b[?6h");
+    assert_eq!(vt.scroll_region(), (4, 19));
+    assert!(vt.origin_mode());
+    vt.set_size(10, 80);
+    assert_eq!(vt.scroll_region(), (4, 9));
+  }
+
+  #[test]
+  fn palette() {
+    let mut vt = Vt::new(24, 80, 0);
+    vt.set_color_replies(true);
+    vt.process(b"\x1b]4;1;rgb:12/34/56\x07\x1b]4;1;?\x07");
+    assert_eq!(vt.palette().rgb(1), [0x12, 0x34, 0x56]);
+    assert_eq!(vt.take_replies(), b"\x1b]4;1;rgb:1212/3434/5656\x07");
+  }
+
+  #[test]
+  fn logical_lines() {
+    let mut vt = Vt::new(5, 5, 0);
+    vt.process(b"hello world\r\nab\r\n");
+    assert!(vt.screen().row_wrapped(0));
+    assert!(vt.screen().row_wrapped(1));
+    assert!(!vt.screen().row_wrapped(2));
+    assert_eq!(vt.logical_lines(), vec!["hello world", "ab"]);
+
+    assert!(Vt::new(2, 5, 0).logical_lines().is_empty());
+  }
+
+  #[test]
+  fn raw_tail() {
+    let mut vt = Vt::new(2, 10, 0);
+    vt.process(b"off");
+    assert_eq!(vt.raw_tail(10), b"");
+
+    vt.set_raw_capacity(8);
+    vt.process(b"\x1b[1mab");
+    assert_eq!(vt.raw_tail(100), b"\x1b[1mab");
+    assert_eq!(vt.raw_tail(2), b"ab");
+    vt.process(b"cdef");
+    assert_eq!(vt.raw_tail(100), b"1mabcdef");
+    vt.process(b"0123456789");
+    assert_eq!(vt.raw_tail(100), b"23456789");
+  }
+
+  #[test]
+  fn title_changes() {
+    let mut vt = Vt::new(2, 10, 0);
+    vt.process(b"\x1b]0;vim\x07\x1b]1;icon\x07\x1b]2;vim\x1b\\\x1b]2;");
+    vt.process(b"bash\x07");
+    let titles: Vec<_> = vt
+      .title_changes()
+      .iter()
+      .map(|c| c.title.as_str())
+      .collect();
+    assert_eq!(titles, ["vim", "bash"]);
+    assert_eq!(vt.screen().title(), "bash");
+
+    for i in 0..MAX_TITLE_CHANGES {
+      vt.process(format!("\x1b]2;{}\x07", i).as_bytes());
+    }
+    assert_eq!(vt.title_changes().len(), MAX_TITLE_CHANGES);
+    assert_eq!(vt.title_changes()[0].title, "0");
+  }
+
+  #[test]
+  fn tab_width() {
+    let mut vt = Vt::new(2, 30, 0);
+    vt.process(b"\tx\tabc\t");
+    assert_eq!(vt.screen().cell(0, TAB_WIDTH).unwrap().contents(), "x");
+    assert_eq!(vt.screen().cell(0, 2 * TAB_WIDTH).unwrap().contents(), "a");
+    assert_eq!(vt.screen().cursor_position(), (0, 3 * TAB_WIDTH));
+  }
+
+  #[test]
+  fn cell_changed_ago() {
+    let mut vt = Vt::new(2, 10, 0);
+    assert_eq!(vt.cell_changed_ago(0, 0), None);
+    vt.track_cells();
+    vt.process(b"ab");
+    std::thread::sleep(Duration::from_millis(50));
+    // Rewriting the same content isn't a change.
+    vt.process(b"\ra\x1b[1mb");
+    let ago = |vt: &Vt, col| vt.cell_changed_ago(0, col).unwrap();
+    assert!(ago(&vt, 0) >= Duration::from_millis(50));
+    assert!(ago(&vt, 1) < Duration::from_millis(50));
+    assert_eq!(vt.cell_changed_ago(0, 10), None);
+    assert_eq!(vt.cell_changed_ago(2, 0), None);
+
+    vt.set_size(3, 5);
+    assert!(ago(&vt, 0) < Duration::from_millis(50));
+    assert!(vt.cell_changed_ago(2, 4).is_some());
+  }
+
+  #[test]
+  fn fps() {
+    let mut vt = Vt::new(2, 10, 0);
+    // Not tracked yet.
+    vt.process(b"x");
+    assert_eq!(vt.fps(), 0);
+    vt.track_cells();
+    vt.process(b"a");
+    vt.process(b"b");
+    // Nothing changed.
+    vt.process(b"\r");
+    assert_eq!(vt.fps(), 2);
+    // A synchronized update is one frame.
+    vt.process(b"\x1b[?2026hc");
+    vt.process(b"d");
+    assert_eq!(vt.fps(), 2);
+    vt.process(b"e\x1b[?2026l");
+    assert_eq!(vt.fps(), 3);
+  }
+
+  #[test]
+  fn screen_diff() {
+    let mut a = vt100::Parser::new(2, 3, 0);
+    a.process(b"abc");
+    assert_eq!(super::screen_diff(a.screen(), a.screen(), 10), None);
+
+    let mut b = vt100::Parser::new(2, 3, 0);
+    b.process(b"a\x1b[1;31mb\x1b[0m\x1b[48;2;16;32;48mX");
+    assert_eq!(
+      super::screen_diff(a.screen(), b.screen(), 10).unwrap(),
+      "2 cells differ\n  \
+       x=1 y=0: \"b\" -> \"b\" bold fg=1\n  \
+       x=2 y=0: \"c\" -> \"X\" bg=#102030"
+    );
+    assert_eq!(
+      super::screen_diff(a.screen(), b.screen(), 1).unwrap(),
+      "2 cells differ\n  \
+       x=1 y=0: \"b\" -> \"b\" bold fg=1\n  \
+       ... 1 more cells"
+    );
+
+    let mut c = vt100::Parser::new(1, 4, 0);
+    c.process(b"abc");
+    assert_eq!(
+      super::screen_diff(a.screen(), c.screen(), 10).unwrap(),
+      "4 cells differ\n  \
+       size: 3x2 -> 4x1\n  \
+       x=3 y=0: none -> \"\"\n  \
+       x=0 y=1: \"\" -> none\n  \
+       x=1 y=1: \"\" -> none\n  \
+       x=2 y=1: \"\" -> none"
+    );
+  }
+
+  #[test]
+  fn watch_changes() {
+    let mut vt = Vt::new(3, 10, 0);
+    vt.process(b"top\r\n\r\nstatus");
+    let id = vt.add_watch(2..3, 0..10);
+    std::thread::sleep(Duration::from_millis(5));
+    vt.process(b"\x1b[2;1Hmiddle");
+    assert_eq!(vt.watch_changes(id), Some(vec![]));
+    // Overdrawn, even if restored later.
+    vt.process(b"\x1b[3;3HX");
+    vt.process(b"\x1b[3;3Ha");
+    assert_eq!(vt.watch_changes(id), Some(vec![(2, 2)]));
+    assert_eq!(vt.watch_changes(id + 1), None);
+    // Outside of the screen.
+    let id = vt.add_watch(2..5, 8..12);
+    assert_eq!(vt.watch_changes(id), Some(vec![]));
+  }
+
+  #[test]
+  fn count_raw() {
+    let mut vt = Vt::new(2, 10, 0);
+    vt.process(b"ok ok ");
+    assert_eq!(vt.count_raw("ok"), 0);
+    vt.set_raw_capacity(8);
+    vt.process(b"o");
+    vt.process(b"k\x1b[2Jok  ok");
+    // The first "ok" is dropped from the buffer.
+    assert_eq!(vt.count_raw("ok"), 2);
+  }
+
+  #[test]
+  fn raw_input() {
+    let mut vt = Vt::new(2, 10, 0);
+    vt.set_raw_capacity(100);
+    vt.mark_input(b"off");
+    vt.set_raw_input(true);
+    vt.process(b"$ ");
+    vt.mark_input(b"ls\r\x1b\\");
+    vt.process(b"ls");
+    assert_eq!(
+      vt.raw_tail(100),
+      b"$ \x1b_virterm-input;ls\\r\\x1b\\\\\x1b\\ls"
+    );
+
+    // The markers don't change the screen.
+    let mut replayed = Vt::new(2, 10, 0);
+    replayed.process(&vt.raw_tail(100));
+    assert_eq!(replayed.screen().contents(), "$ ls");
+  }
+
+  #[test]
+  fn stream_counter() {
+    let mut vt = Vt::new(2, 10, 0);
+    vt.process(b"retry ");
+    let id = vt.add_counter("retry");
+    assert_eq!(vt.counter(id), 0);
+
+    vt.process(b"retry re");
+    assert_eq!(vt.counter(id), 1);
+    vt.process(b"try retryretry");
+    assert_eq!(vt.counter(id), 4);
+
+    let overlapping = vt.add_counter("aa");
+    vt.process(b"aaa");
+    assert_eq!(vt.counter(overlapping), 1);
+
+    vt.remove_counter(id);
+    assert_eq!(vt.counter(id), 0);
+  }
+
+  #[test]
+  fn strip_escapes_from_output() {
+    assert_eq!(
+      strip_escapes(b"\x1b[1;31mred\x1b[0m \x1b]0;title\x07ok\r\n"),
+      b"red ok\r\n"
+    );
+    assert_eq!(
+      strip_escapes(b"\x1b]8;;url\x1b\\link\x1b(Bx\x1b=y"),
+      b"linkxy"
+    );
+  }
+
+  #[test]
+  fn capture() {
+    let mut vt = Vt::new(2, 10, 0);
+    vt.process(b"before");
+    let id = vt.start_capture();
+    vt.process(b"one ");
+    vt.process(b"two");
+    assert_eq!(vt.captured(id), b"one two");
+    assert_eq!(vt.stop_capture(id), b"one two");
+    assert_eq!(vt.captured(id), b"");
+  }
+
+  #[test]
+  fn take_bell() {
+    let mut vt = Vt::new(2, 10, 0);
+    assert!(!vt.take_bell());
+    vt.process(b"a\x07b");
+    assert!(vt.take_bell());
+    assert!(!vt.take_bell());
+    vt.process(b"\x1bg");
+    assert!(vt.take_bell());
+  }
+
+  #[test]
+  fn non_blank_cells() {
+    let mut vt = Vt::new(2, 10, 0);
+    assert_eq!(vt.non_blank_cells(), 0);
+    vt.process("a b\x1b[41m  \x1b[0m\r\n日本".as_bytes());
+    assert_eq!(vt.non_blank_cells(), 4);
+  }
+
+  #[test]
+  fn rows_with_scrollback() {
+    let mut vt = Vt::new(2, 5, 10);
+    vt.process(b"1\r\n2\r\n3\r\n4\r\n5\r\n6");
+    let text = |rows: Vec<Vec<Option<vt100::Cell>>>| {
+      rows
+        .iter()
+        .map(|row| row.iter().flatten().map(|c| c.contents()).collect())
+        .collect::<Vec<String>>()
+    };
+    assert_eq!(
+      text(vt.rows_with_scrollback(usize::MAX)),
+      ["1", "2", "3", "4", "5", "6"]
+    );
+    assert_eq!(text(vt.rows_with_scrollback(1)), ["4", "5", "6"]);
+    assert_eq!(vt.screen().scrollback(), 0);
+    assert_eq!(vt.screen().size(), (2, 5));
+    assert_eq!(vt.screen().contents(), "5\n6");
+    assert_eq!(vt.contents_with_scrollback(), "1\n2\n3\n4\n5\n6");
+
+    let mut vt = Vt::new(3, 5, 10);
+    vt.process(b"1\r\n2");
+    assert_eq!(vt.contents_with_scrollback(), "1\n2");
+  }
+
+  #[test]
+  fn set_scrollback() {
+    let mut vt = Vt::new(2, 5, 10);
+    vt.process(b"1\r\n2\r\n3\r\n4");
+    vt.set_scrollback(1);
+    assert_eq!(vt.screen().contents(), "2\n3");
+    // New rows keep the view in place.
+    vt.process(b"\r\n5");
+    assert_eq!(vt.scrollback_offset(), 2);
+    assert_eq!(vt.screen().contents(), "2\n3");
+    vt.process(b"\r\n6");
+    assert_eq!(vt.scrollback_offset(), 3);
+    assert_eq!(vt.screen().contents(), "2\n3");
+    vt.set_scrollback(0);
+    assert_eq!(vt.screen().contents(), "5\n6");
+  }
+
+  #[test]
+  fn set_scrollback_pages() {
+    let mut vt = Vt::new(2, 5, 10);
+    vt.process(b"1\r\n2\r\n\x1b[1m3\x1b[0m\r\n4\r\n5\r\n6\r\n7\r\n8");
+    let page = |vt: &mut Vt, offset| {
+      vt.set_scrollback(offset);
+      vt.screen().contents()
+    };
+    assert_eq!(page(&mut vt, 2), "5\n6");
+    assert_eq!(page(&mut vt, 4), "3\n4");
+    assert!(vt.screen().cell(0, 0).unwrap().bold());
+    assert_eq!(page(&mut vt, 6), "1\n2");
+    // Limited to the scrollback length.
+    assert_eq!(page(&mut vt, 100), "1\n2");
+    assert_eq!(vt.scrollback_offset(), 6);
+    // The cursor and the input modes are the live ones.
+    vt.process(b"\x1b[?1h");
+    assert_eq!(vt.screen().cursor_position(), (1, 1));
+    assert!(vt.screen().application_cursor());
+
+    vt.set_scrollback(5);
+    vt.process(b"\r\n9");
+    assert_eq!(vt.scrollback_offset(), 6);
+    assert_eq!(vt.screen().contents(), "2\n3");
+    // Resizing keeps the rows in view.
+    vt.set_size(4, 5);
+    assert_eq!(vt.screen().contents(), "2\n3\n4\n5");
+    vt.set_scrollback(0);
+    assert_eq!(vt.screen().contents(), "8\n9");
+  }
+
+  #[test]
+  fn mode_transitions() {
+    let mut vt = Vt::new(2, 10, 0);
+    assert!(vt.process(b"hello").is_empty());
+
+    let transitions = vt.process(b"\x1b[?1049h\x1b[?1000h");
+    let changes: Vec<_> = transitions
+      .iter()
+      .map(|t| (t.mode, t.value.as_str()))
+      .collect();
+    assert_eq!(
+      changes,
+      [
+        ("alternate_screen", "on"),
+        ("mouse_protocol_mode", "PressRelease")
+      ]
+    );
+
+    // Toggled within one chunk.
+    assert!(vt.process(b"\x1b[?2004h\x1b[?2004l").is_empty());
+    vt.process(b"\x1b[?1049l");
+    assert_eq!(vt.mode_transitions().len(), 3);
+    assert_eq!(vt.mode_transitions().back().unwrap().value, "off");
+  }
+}